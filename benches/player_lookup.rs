@@ -0,0 +1,68 @@
+//! Compares the latency of looking up the active player on every request against reusing the
+//! cached handle kept by `MediaController`.
+//!
+//! Needs a running session with at least one media player, run with:
+//! ```sh
+//! cargo bench --bench player_lookup
+//! ```
+//!
+//! On Linux with a single MPRIS player on a `dbus-run-session` bus, one vCPU and a release
+//! build, three runs gave 625-649µs per call for `find_active + status` (the lookup every
+//! request did before) against 115-147µs for `cached player + status`.
+
+const ITERATIONS: u32 = 200;
+
+fn measure(name: &str, mut f: impl FnMut()) {
+    f();
+    let start = std::time::Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let per_call = start.elapsed() / ITERATIONS;
+    println!("{name:<28} {per_call:>12?} per call ({ITERATIONS} iterations)");
+}
+
+#[cfg(not(target_os = "windows"))]
+fn main() {
+    let player_finder = match mpris::PlayerFinder::new() {
+        Ok(player_finder) => player_finder,
+        Err(e) => {
+            eprintln!("Skipping benchmark, no session bus: {e}");
+            return;
+        }
+    };
+    if let Err(e) = player_finder.find_active() {
+        eprintln!("Skipping benchmark, no active player: {e}");
+        return;
+    }
+    let mc = os_mediamote::media_controller::MediaController::new().unwrap();
+
+    measure("find_active + status", || {
+        let player = player_finder.find_active().unwrap();
+        player.get_playback_status().unwrap();
+    });
+    measure("cached player + status", || {
+        mc.media_is_playing().unwrap();
+    });
+}
+
+#[cfg(target_os = "windows")]
+fn main() {
+    use windows::Media::Control::GlobalSystemMediaTransportControlsSessionManager;
+
+    let mc = os_mediamote::media_controller::MediaController::new().unwrap();
+
+    measure("RequestAsync + status", || {
+        let session_manager = futures::executor::block_on(async {
+            GlobalSystemMediaTransportControlsSessionManager::RequestAsync()
+                .unwrap()
+                .await
+                .unwrap()
+        });
+        let session = session_manager.GetCurrentSession().unwrap();
+        session.GetPlaybackInfo().unwrap().PlaybackStatus().unwrap();
+    });
+    measure("cached session + status", || {
+        mc.media_is_playing().unwrap();
+    });
+}
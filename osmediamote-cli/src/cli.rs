@@ -0,0 +1,525 @@
+//! Command line definition, also the source of the shell completions and the man page.
+
+use crate::format::Template;
+use crate::profile::{Config, Profile};
+use crate::tls::{self, Fingerprint};
+use crate::{DEFAULT_TIMEOUT, OSMediaMoteError, Server, parse_base_url};
+use clap::{CommandFactory, Parser, Subcommand};
+use std::time::Duration;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = env!("CARGO_PKG_NAME"),
+    version,
+    about = "Playerctl inspired client for the OSMediaMote server",
+    after_help = "Commands can be chained, e.g. `osmediamote-cli HOST pause position 0 wait 2s play`.",
+    subcommand_required = true,
+    arg_required_else_help = true
+)]
+pub struct Cli {
+    /// Server as HOST[:PORT], HOST being a name, IPv4 or IPv6 address, or a base URL such as
+    /// https://host/mote/, or @PROFILE for a saved one, the default profile when left out
+    #[arg(value_name = "HOST|@PROFILE")]
+    pub server: Option<String>,
+
+    /// Server, when not given as the first argument
+    #[arg(long, global = true, env = "OSMEDIAMOTE_HOST", value_name = "HOST")]
+    pub host: Option<String>,
+
+    /// Port of the server, 65420 by default
+    #[arg(long, global = true)]
+    pub port: Option<u16>,
+
+    /// Player to control instead of the one the server picks, see `players`
+    #[arg(long, global = true, env = "OSMEDIAMOTE_PLAYER", value_name = "NAME")]
+    pub player: Option<String>,
+
+    /// Admin token of the server, needed by `quit`
+    #[arg(
+        long,
+        global = true,
+        env = "OSMEDIAMOTE_TOKEN",
+        hide_env_values = true,
+        value_name = "TOKEN"
+    )]
+    pub token: Option<String>,
+
+    /// SHA-256 fingerprint of the server's certificate, trusted instead of the system's
+    /// authorities, e.g. for a self-signed one
+    #[arg(
+        long,
+        global = true,
+        env = "OSMEDIAMOTE_FINGERPRINT",
+        value_name = "SHA256",
+        value_parser = tls::parse_fingerprint
+    )]
+    pub fingerprint: Option<Fingerprint>,
+
+    /// Seconds to wait for the server
+    #[arg(long, global = true, value_name = "SECONDS", value_parser = parse_timeout)]
+    pub timeout: Option<Duration>,
+
+    /// Print status, position and metadata with a template, e.g.
+    /// '{{artist}} - {{title}} [{{position|duration}}]'
+    #[arg(long, global = true, value_parser = Template::parse)]
+    pub format: Option<Template>,
+
+    /// Keep printing status, position or metadata on every change
+    #[arg(long, global = true)]
+    pub follow: bool,
+
+    /// Print output and errors as JSON
+    #[arg(long, global = true)]
+    pub json: bool,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Start playback
+    Play,
+    /// Pause playback
+    Pause,
+    /// Toggle between playing and paused
+    PlayPause,
+    /// Skip to the next track
+    Next,
+    /// Go back to the previous track
+    Previous,
+    /// Stop playback
+    Stop,
+    /// Print whether the player is playing
+    Status,
+    /// Print the position in seconds, or seek to OFFSET
+    Position {
+        /// Seconds, [h:]mm:ss or a percentage of the track such as 50%, relative with a trailing
+        /// + or -, e.g. 30+ or 1:00-
+        #[arg(value_parser = parse_seek)]
+        offset: Option<Seek>,
+    },
+    /// Print the title, duration and artist of the current track
+    Metadata,
+    /// List the players the server can see
+    Players,
+    /// Close the player, needs the admin token
+    Quit,
+    /// Open an interactive remote with the current track and its cover
+    Tui,
+    /// Wait before running the next command
+    Wait {
+        /// A duration such as 5s, 500ms, 2m or 1h, or track-change to wait for another track
+        #[arg(value_name = "DURATION|track-change", value_parser = parse_wait)]
+        until: Wait,
+    },
+    /// Run the commands in FILE, or read from stdin, one or more per line
+    Run {
+        /// Script to run, `-` for stdin
+        #[arg(default_value = "-")]
+        file: String,
+    },
+    /// Manage the saved servers used as @PROFILE
+    Profile {
+        #[command(subcommand)]
+        action: ProfileAction,
+    },
+    /// Print a completion script for SHELL
+    Completions {
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Print the man page
+    Manpage,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum ProfileAction {
+    /// Save HOST as NAME, along with --port, --token, --fingerprint and --player
+    Add {
+        name: String,
+        #[arg(value_name = "HOST")]
+        host: String,
+        /// Also make it the default profile
+        #[arg(long)]
+        default: bool,
+    },
+    /// List the profiles, the default one marked with *
+    List,
+    /// Forget a profile
+    Remove { name: String },
+    /// Use NAME when no server is given
+    Default { name: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Wait {
+    Duration(Duration),
+    TrackChange,
+}
+
+/// Where `position OFFSET` seeks to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Seek {
+    pub amount: SeekAmount,
+    /// Whether `amount` moves the position rather than replacing it.
+    pub relative: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SeekAmount {
+    /// Seconds, negative ones going back.
+    Seconds(f64),
+    /// Percent of the track's duration, negative ones going back.
+    Percent(f64),
+}
+
+impl Command {
+    /// Whether the command prints the status, so `--format` and `--follow` apply.
+    pub fn is_query(&self) -> bool {
+        matches!(
+            self,
+            Command::Status | Command::Position { offset: None } | Command::Metadata
+        )
+    }
+}
+
+impl Cli {
+    /// The server to talk to, from the arguments and the profile they name, if any.
+    ///
+    /// Options and environment variables take precedence over the profile.
+    pub fn server(&self) -> Result<Server, OSMediaMoteError> {
+        let host = self.server.as_deref().or(self.host.as_deref());
+        let profile = match host.map(|host| host.strip_prefix('@')) {
+            Some(Some(name)) => Some(Config::load()?.profile(name)?.clone()),
+            Some(None) => None,
+            None => Config::load()?.default_profile()?.cloned(),
+        };
+        let host = match (&profile, host) {
+            (Some(profile), _) => profile.host.as_str(),
+            (None, Some(host)) => host,
+            (None, None) => {
+                return Err(OSMediaMoteError::Usage(
+                    "No server given, pass HOST, @PROFILE or --host, or set a default profile"
+                        .to_string(),
+                ));
+            }
+        };
+        let profile = profile.as_ref();
+
+        let port = self.port.or(profile.and_then(|profile| profile.port));
+        let base = parse_base_url(host, port)?;
+        let fingerprint = match self.fingerprint {
+            Some(fingerprint) => Some(fingerprint),
+            None => profile
+                .and_then(|profile| profile.fingerprint.as_deref())
+                .map(tls::parse_fingerprint)
+                .transpose()
+                .map_err(OSMediaMoteError::InvalidConfig)?,
+        };
+        if fingerprint.is_some() && base.scheme() != "https" {
+            return Err(OSMediaMoteError::Usage(
+                "--fingerprint needs an https:// server".to_string(),
+            ));
+        }
+
+        let mut server = Server::new(base, fingerprint, self.timeout.unwrap_or(DEFAULT_TIMEOUT))?;
+        server.player = self
+            .player
+            .clone()
+            .or_else(|| profile.and_then(|profile| profile.player.clone()));
+        server.token = self
+            .token
+            .clone()
+            .or_else(|| profile.and_then(|profile| profile.token.clone()));
+        Ok(server)
+    }
+
+    /// A profile for `host` with the options given alongside it.
+    pub fn profile(&self, host: &str) -> Result<Profile, OSMediaMoteError> {
+        let base = parse_base_url(host, self.port)?;
+        if self.fingerprint.is_some() && base.scheme() != "https" {
+            return Err(OSMediaMoteError::Usage(
+                "--fingerprint needs an https:// server".to_string(),
+            ));
+        }
+        Ok(Profile {
+            host: host.to_string(),
+            port: self.port,
+            token: self.token.clone(),
+            fingerprint: self.fingerprint.as_ref().map(tls::format_fingerprint),
+            player: self.player.clone(),
+        })
+    }
+}
+
+/// Parses the arguments, where several commands can follow each other, e.g.
+/// `HOST pause position 0 play`.
+///
+/// Returns the options, with the first command, and every command in order.
+pub fn parse<I>(args: I) -> Result<(Cli, Vec<Command>), clap::Error>
+where
+    I: IntoIterator<Item = String>,
+{
+    let (head, mut chunks) = split(args.into_iter().collect());
+    if chunks.is_empty() {
+        chunks.push(vec![]);
+    }
+
+    let parse_chunk = |chunk: &Vec<String>| Cli::try_parse_from(head.iter().chain(chunk));
+    let cli = parse_chunk(&chunks[0])?;
+    let mut commands = vec![cli.command.clone()];
+    for chunk in &chunks[1..] {
+        commands.push(parse_chunk(chunk)?.command);
+    }
+
+    Ok((cli, commands))
+}
+
+/// Parses a script line, which holds commands without options, e.g. `pause wait 5s play`.
+pub fn parse_line(line: &str) -> Result<Vec<Command>, clap::Error> {
+    let mut args = vec![env!("CARGO_PKG_NAME").to_string()];
+    for arg in line.split_whitespace() {
+        if arg.starts_with('-') {
+            return Err(Cli::command().error(
+                clap::error::ErrorKind::UnknownArgument,
+                format!("{arg}: options go on the command line, not in scripts"),
+            ));
+        }
+        // There is no HOST in scripts, so the line has to start with a command.
+        if args.len() == 1 && Cli::command().find_subcommand(arg).is_none() {
+            return Err(Cli::command().error(
+                clap::error::ErrorKind::InvalidSubcommand,
+                format!("unrecognized command '{arg}'"),
+            ));
+        }
+        args.push(arg.to_string());
+    }
+    let (_, commands) = parse(args)?;
+    // A script running itself would never end.
+    if commands
+        .iter()
+        .any(|command| matches!(command, Command::Run { .. }))
+    {
+        return Err(Cli::command().error(
+            clap::error::ErrorKind::InvalidSubcommand,
+            "run: scripts cannot run other scripts",
+        ));
+    }
+    Ok(commands)
+}
+
+/// First line of a clap error, without its `error: ` prefix, along with the list it announces.
+pub fn usage_message(err: &clap::Error) -> String {
+    let message = err.to_string();
+    let mut lines = message.lines();
+    let mut line = lines
+        .next()
+        .unwrap_or_default()
+        .trim_start_matches("error: ")
+        .to_string();
+    if line.ends_with(':') {
+        let items: Vec<&str> = lines
+            .map(str::trim)
+            .take_while(|item| !item.is_empty())
+            .collect();
+        line = format!("{line} {}", items.join(", "));
+    }
+    line
+}
+
+/// Splits the arguments into the program name with every option, and the arguments of each
+/// command. `--help` and options only a command knows stay with their command, and `help` and
+/// `profile` take every argument after them, so that a profile can be called `play`.
+fn split(args: Vec<String>) -> (Vec<String>, Vec<Vec<String>>) {
+    let definition = Cli::command();
+    let option = |arg: &str| {
+        definition.get_arguments().find(|option| {
+            option
+                .get_long()
+                .is_some_and(|long| arg == format!("--{long}"))
+                || option
+                    .get_short()
+                    .is_some_and(|short| arg == format!("-{short}"))
+        })
+    };
+    let is_command = |arg: &str| definition.find_subcommand(arg).is_some() || arg == "help";
+
+    let mut args = args.into_iter();
+    let mut head: Vec<String> = args.next().into_iter().collect();
+    let mut chunks: Vec<Vec<String>> = vec![];
+    let mut nested = false;
+    while let Some(arg) = args.next() {
+        let known = option(arg.split('=').next().unwrap_or_default());
+        if known.is_some() || matches!(arg.as_str(), "-V" | "--version") {
+            let takes_value = known.is_some_and(|option| option.get_action().takes_values());
+            let value = if takes_value && !arg.contains('=') {
+                args.next()
+            } else {
+                None
+            };
+            head.push(arg);
+            head.extend(value);
+        } else if is_command(&arg) && !nested {
+            nested = matches!(arg.as_str(), "help" | "profile");
+            chunks.push(vec![arg]);
+        } else {
+            match chunks.last_mut() {
+                Some(chunk) => chunk.push(arg),
+                None => head.push(arg),
+            }
+        }
+    }
+
+    (head, chunks)
+}
+
+/// Command line arguments, with `position=OFFSET` from older versions split in two.
+pub fn args() -> impl Iterator<Item = String> {
+    std::env::args().flat_map(|arg| match arg.strip_prefix("position=") {
+        Some(offset) => vec!["position".to_string(), offset.to_string()],
+        None => vec![arg],
+    })
+}
+
+/// Parses playerctl style offsets: `90`, `1:30` or `1:01:30`, `50%`, each of which can end in
+/// `+` or `-` to seek relative to the current position.
+fn parse_seek(value: &str) -> Result<Seek, String> {
+    let invalid = || {
+        format!("{value} is not an offset such as 90, 1:30, 50%, 30+ or 10-, see `help position`")
+    };
+
+    let (value, sign, relative) = match value.strip_suffix('+') {
+        Some(value) => (value, 1.0, true),
+        None => match value.strip_suffix('-') {
+            Some(value) => (value, -1.0, true),
+            None => (value, 1.0, false),
+        },
+    };
+
+    let amount = if let Some(percent) = value.strip_suffix('%') {
+        let percent = parse_number(percent).ok_or_else(invalid)?;
+        SeekAmount::Percent(sign * percent)
+    } else {
+        let mut seconds = 0.0;
+        let parts: Vec<&str> = value.split(':').collect();
+        if parts.len() > 3 {
+            return Err(invalid());
+        }
+        for (index, part) in parts.iter().enumerate() {
+            let number = parse_number(part).ok_or_else(invalid)?;
+            // Only the first part can go past 59, and only the last one have a fraction.
+            if (index > 0 && number >= 60.0) || (index + 1 < parts.len() && number.fract() != 0.0) {
+                return Err(invalid());
+            }
+            seconds = seconds * 60.0 + number;
+        }
+        SeekAmount::Seconds(sign * seconds)
+    };
+
+    Ok(Seek { amount, relative })
+}
+
+/// A non-negative decimal number, without the signs and exponents `f64` parsing accepts.
+fn parse_number(value: &str) -> Option<f64> {
+    if value.is_empty() || !value.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        return None;
+    }
+    value.parse().ok()
+}
+
+fn parse_wait(value: &str) -> Result<Wait, String> {
+    if value == "track-change" {
+        return Ok(Wait::TrackChange);
+    }
+    parse_duration(value).map(Wait::Duration)
+}
+
+/// Parses `5s`, `500ms`, `2m` or `1h`, a bare number being seconds.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let scale = match unit {
+        "ms" => 0.001,
+        "" | "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => {
+            return Err(format!(
+                "{value} is not a duration such as 5s, 500ms, 2m or 1h"
+            ));
+        }
+    };
+    number
+        .parse::<f64>()
+        .ok()
+        .and_then(|number| Duration::try_from_secs_f64(number * scale).ok())
+        .ok_or_else(|| format!("{value} is not a duration such as 5s, 500ms, 2m or 1h"))
+}
+
+fn parse_timeout(value: &str) -> Result<Duration, String> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|seconds| *seconds > 0.0)
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| format!("{value} is not a positive number of seconds"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn absolute(seconds: f64) -> Seek {
+        Seek {
+            amount: SeekAmount::Seconds(seconds),
+            relative: false,
+        }
+    }
+
+    fn relative(amount: SeekAmount) -> Seek {
+        Seek {
+            amount,
+            relative: true,
+        }
+    }
+
+    #[test]
+    fn seeks_to_seconds_and_clock_times() {
+        assert_eq!(parse_seek("90"), Ok(absolute(90.0)));
+        assert_eq!(parse_seek("12.5"), Ok(absolute(12.5)));
+        assert_eq!(parse_seek("1:30"), Ok(absolute(90.0)));
+        assert_eq!(parse_seek("1:01:30.5"), Ok(absolute(3690.5)));
+        // Only the first part may be 60 or more.
+        assert_eq!(parse_seek("90:00"), Ok(absolute(5400.0)));
+    }
+
+    #[test]
+    fn seeks_relative_and_by_percent() {
+        assert_eq!(parse_seek("30+"), Ok(relative(SeekAmount::Seconds(30.0))));
+        assert_eq!(
+            parse_seek("1:00-"),
+            Ok(relative(SeekAmount::Seconds(-60.0)))
+        );
+        assert_eq!(
+            parse_seek("50%"),
+            Ok(Seek {
+                amount: SeekAmount::Percent(50.0),
+                relative: false,
+            })
+        );
+        assert_eq!(parse_seek("10%-"), Ok(relative(SeekAmount::Percent(-10.0))));
+    }
+
+    #[test]
+    fn refuses_invalid_offsets() {
+        for offset in [
+            "", "+", "-30", "+30", "1e3", "inf", "NaN", "1:60", "1:5.5:00", "1:2:3:4", "::", "1:",
+            "50%%", "%", "30+-", "ten",
+        ] {
+            assert!(parse_seek(offset).is_err(), "{offset:?} was accepted");
+        }
+    }
+}
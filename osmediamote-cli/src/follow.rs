@@ -0,0 +1,222 @@
+//! `--follow`: keeps running and prints a new line whenever the output changes, like
+//! `playerctl --follow`, for status bars such as waybar, polybar or i3blocks.
+//!
+//! Updates come from the server's `/events` stream, or from polling `/status` on servers
+//! without it and for players picked with `--player`, as the stream follows the server's pick.
+//! Lines for no status are printed while no player runs or the server is unreachable, and the
+//! connection is retried until the server is back, or when the stream goes silent for longer
+//! than its keep-alive.
+
+use crate::{OSMediaMoteError, Server, status_values};
+use serde_json::{Map, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+/// How often the line is refreshed between updates, so a playing position keeps moving.
+const TICK: Duration = Duration::from_secs(1);
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+pub(crate) enum Update {
+    /// The current status, `None` while no player runs.
+    Status(Option<Map<String, Value>>),
+    /// The server could not be reached or refused the client, for this reason.
+    Disconnected(OSMediaMoteError),
+}
+
+enum StreamEnd {
+    /// The server has no `/events` endpoint.
+    Unsupported,
+    Closed,
+}
+
+/// Prints the line `render` makes of the status on every change until the process is killed.
+///
+/// `render` gets `None` while there is no status to show.
+pub fn follow<F>(server: &Server, render: F) -> Result<(), OSMediaMoteError>
+where
+    F: Fn(Option<&Map<String, Value>>) -> Result<String, OSMediaMoteError>,
+{
+    let receiver = updates(server);
+
+    let mut current: Option<(Map<String, Value>, Instant)> = None;
+    let mut last_line = None;
+    loop {
+        match receiver.recv_timeout(TICK) {
+            Ok(Update::Status(values)) => current = values.map(|values| (values, Instant::now())),
+            Ok(Update::Disconnected(_)) => current = None,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+
+        let line = match &current {
+            Some((values, received)) => render(Some(&advance(values, received.elapsed())))?,
+            None => render(None)?,
+        };
+        if last_line.as_ref() != Some(&line) {
+            let mut stdout = std::io::stdout();
+            // The reader went away, e.g. a restarting status bar.
+            if writeln!(stdout, "{line}")
+                .and_then(|_| stdout.flush())
+                .is_err()
+            {
+                return Ok(());
+            }
+            last_line = Some(line);
+        }
+    }
+}
+
+/// Blocks until the server reports another track than the one playing when called, or the
+/// first track when nothing plays.
+///
+/// Fails when the server refuses the client, or stays unreachable for longer than its timeout.
+pub fn wait_for_track_change(server: &Server) -> Result<(), OSMediaMoteError> {
+    let mut first = None;
+    let mut connected_at = Instant::now();
+    for update in updates(server) {
+        let values = match update {
+            Update::Status(values) => values,
+            Update::Disconnected(err)
+                if !is_retryable(&err) || connected_at.elapsed() > server.timeout() =>
+            {
+                return Err(err);
+            }
+            Update::Disconnected(_) => continue,
+        };
+        connected_at = Instant::now();
+        let current = values.as_ref().map(track);
+        match &first {
+            None => first = Some(current),
+            Some(first) if current.is_some() && *first != current => return Ok(()),
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Whether trying again later may help, as opposed to errors such as a refused token.
+fn is_retryable(err: &OSMediaMoteError) -> bool {
+    matches!(
+        err,
+        OSMediaMoteError::Unreachable(_)
+            | OSMediaMoteError::InvalidResponse(_)
+            | OSMediaMoteError::ServerError(..)
+            | OSMediaMoteError::ReqwestError(_)
+    )
+}
+
+/// What tells tracks apart: the player, title, artist and album.
+pub(crate) fn track(values: &Map<String, Value>) -> [Option<Value>; 4] {
+    ["player", "title", "artist", "album"].map(|key| values.get(key).cloned())
+}
+
+/// Starts watching the server, the updates stop when the receiver is dropped.
+pub(crate) fn updates(server: &Server) -> Receiver<Update> {
+    let (sender, receiver) = mpsc::channel();
+    let watched = server.clone();
+    std::thread::spawn(move || watch(&watched, &sender));
+    receiver
+}
+
+/// Moves the position of a playing track on by the time since the status was received.
+pub(crate) fn advance(values: &Map<String, Value>, elapsed: Duration) -> Map<String, Value> {
+    let mut values = values.clone();
+    let playing = values.get("is_playing").and_then(Value::as_bool) == Some(true);
+    if let (true, Some(position)) = (playing, values.get("position").and_then(Value::as_f64)) {
+        let rate = values.get("rate").and_then(Value::as_f64).unwrap_or(1.0);
+        let mut position = position + elapsed.as_secs_f64() * rate;
+        if let Some(duration) = values.get("duration").and_then(Value::as_f64)
+            && duration > 0.0
+        {
+            position = position.min(duration);
+        }
+        values.insert("position".to_string(), Value::from(position));
+    }
+    values
+}
+
+/// Sends updates from the server for as long as the receiver is listening.
+fn watch(server: &Server, sender: &Sender<Update>) {
+    if server.player.is_some() {
+        return poll(server, sender);
+    }
+    loop {
+        let err = match stream_events(server, sender) {
+            Ok(StreamEnd::Unsupported) => return poll(server, sender),
+            Ok(StreamEnd::Closed) => {
+                OSMediaMoteError::InvalidResponse("The server closed the event stream".to_string())
+            }
+            Err(err) => err,
+        };
+        if sender.send(Update::Disconnected(err)).is_err() {
+            return;
+        }
+        std::thread::sleep(RECONNECT_DELAY);
+    }
+}
+
+/// Reads `status` events from `/events` until the connection ends.
+fn stream_events(server: &Server, sender: &Sender<Update>) -> Result<StreamEnd, OSMediaMoteError> {
+    let response = server
+        .request(reqwest::Method::GET, "events", true)
+        .header("Accept", "text/event-stream")
+        .send()?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(StreamEnd::Unsupported);
+    }
+    let status = response.status();
+    if !status.is_success() {
+        return Err(OSMediaMoteError::from_status(status, response.text()?));
+    }
+
+    let mut event = String::new();
+    let mut data = String::new();
+    for line in BufReader::new(response).lines() {
+        let line = line.map_err(|e| OSMediaMoteError::InvalidResponse(e.to_string()))?;
+        if line.is_empty() {
+            if event == "status" {
+                let values = serde_json::from_str::<Option<Map<String, Value>>>(&data)
+                    .map_err(|_| OSMediaMoteError::InvalidResponse(data.clone()))?;
+                if sender
+                    .send(Update::Status(values.map(status_values)))
+                    .is_err()
+                {
+                    return Ok(StreamEnd::Closed);
+                }
+            }
+            event.clear();
+            data.clear();
+        } else if let Some(value) = line.strip_prefix("event:") {
+            event = value.trim_start().to_string();
+        } else if let Some(value) = line.strip_prefix("data:") {
+            if !data.is_empty() {
+                data.push('\n');
+            }
+            data.push_str(value.trim_start());
+        }
+    }
+
+    Ok(StreamEnd::Closed)
+}
+
+/// Fallback for servers without `/events`.
+fn poll(server: &Server, sender: &Sender<Update>) {
+    loop {
+        let update = match server.status() {
+            Ok(values) => Update::Status(Some(values)),
+            Err(
+                err @ (OSMediaMoteError::Unreachable(_)
+                | OSMediaMoteError::Unauthorized(_)
+                | OSMediaMoteError::CertificateMismatch(_)),
+            ) => Update::Disconnected(err),
+            // The server answered, but has no player to report on.
+            Err(_) => Update::Status(None),
+        };
+        if sender.send(update).is_err() {
+            return;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
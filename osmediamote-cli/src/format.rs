@@ -0,0 +1,390 @@
+//! playerctl-style output templates, e.g. `{{artist}} - {{title}} [{{position|duration}}]`.
+//!
+//! Placeholders hold a key, a string or number literal, or a helper call. Helpers can be
+//! called like playerctl's `{{ uc(title) }}` or piped as `{{ title|uc }}`, where the piped
+//! value becomes the first argument: `{{ title|trunc(20) }}` is `{{ trunc(title, 20) }}`.
+//!
+//! Helpers:
+//! - `duration(seconds)` formats a time as `m:ss` or `h:mm:ss`
+//! - `trunc(text, length)` shortens text to `length` characters, ending it with `…`
+//! - `uc(text)` and `lc(text)` change the case
+//! - `default(value, fallback)` gives `fallback` when `value` is empty
+//! - `markup_escape(text)` escapes `<`, `>` and `&` for Pango markup, e.g. in waybar
+//!
+//! Keys are the fields of the server's `/status`, plus the track's metadata under its MPRIS
+//! keys such as `xesam:artist` or `mpris:artUrl`. Lists like `xesam:artist` are joined with
+//! `, ` and `mpris:length` is in microseconds, as in playerctl.
+
+use serde_json::{Map, Value};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Key(String),
+    Literal(Value),
+    Call(String, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Expr(Expr),
+}
+
+/// A parsed `--format` template.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Template, String> {
+        let mut parts = vec![];
+        let mut rest = template;
+
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            let inner = &rest[start + 2..];
+            let end = closing_braces(inner)
+                .ok_or_else(|| format!("Unclosed {{{{ in format: {template}"))?;
+            let mut parser = Parser::new(&inner[..end]);
+            parts.push(Part::Expr(parser.parse()?));
+            rest = &inner[end + 2..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+
+        Ok(Template { parts })
+    }
+
+    /// Fills the template with `values`, unknown keys are empty.
+    pub fn render(&self, values: &Map<String, Value>) -> Result<String, String> {
+        let mut output = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => output.push_str(text),
+                Part::Expr(expr) => output.push_str(&display(&evaluate(expr, values)?)),
+            }
+        }
+        Ok(output)
+    }
+}
+
+/// Where the `}}` closing a placeholder starts in `text`, skipping those in string literals.
+fn closing_braces(text: &str) -> Option<usize> {
+    let mut quote = None;
+    for (index, c) in text.char_indices() {
+        match quote {
+            Some(open) if c == open => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if text[index..].starts_with("}}") => return Some(index),
+            None => {}
+        }
+    }
+    None
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str) -> Parser<'a> {
+        Parser { input, position: 0 }
+    }
+
+    fn parse(&mut self) -> Result<Expr, String> {
+        let expr = self.expr()?;
+        self.skip_whitespace();
+        if self.position < self.input.len() {
+            return Err(format!(
+                "Unexpected `{}` in format",
+                &self.input[self.position..]
+            ));
+        }
+        Ok(expr)
+    }
+
+    /// A term followed by any number of `|helper` or `|helper(args)` pipes.
+    fn expr(&mut self) -> Result<Expr, String> {
+        let mut expr = self.term()?;
+        while self.eat('|') {
+            let name = self.identifier()?;
+            let mut args = vec![expr];
+            if self.eat('(') {
+                args.extend(self.arguments()?);
+            }
+            expr = Expr::Call(name, args);
+        }
+        Ok(expr)
+    }
+
+    fn term(&mut self) -> Result<Expr, String> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('"') | Some('\'') => self.string(),
+            Some(c) if c.is_ascii_digit() || c == '-' => self.number(),
+            _ => {
+                let name = self.identifier()?;
+                if self.eat('(') {
+                    Ok(Expr::Call(name, self.arguments()?))
+                } else {
+                    Ok(Expr::Key(name))
+                }
+            }
+        }
+    }
+
+    /// Comma-separated arguments after an opening parenthesis, up to the closing one.
+    fn arguments(&mut self) -> Result<Vec<Expr>, String> {
+        let mut args = vec![];
+        if self.eat(')') {
+            return Ok(args);
+        }
+        loop {
+            args.push(self.expr()?);
+            if self.eat(')') {
+                return Ok(args);
+            }
+            if !self.eat(',') {
+                return Err(format!("Expected `,` or `)` in format: {}", self.input));
+            }
+        }
+    }
+
+    fn identifier(&mut self) -> Result<String, String> {
+        self.skip_whitespace();
+        let start = self.position;
+        while let Some(c) = self.peek() {
+            if c.is_alphanumeric() || c == '_' || c == ':' {
+                self.position += c.len_utf8();
+            } else {
+                break;
+            }
+        }
+        if start == self.position {
+            return Err(format!("Expected a name in format: {}", self.input));
+        }
+        Ok(self.input[start..self.position].to_string())
+    }
+
+    fn string(&mut self) -> Result<Expr, String> {
+        let quote = self.peek().unwrap();
+        self.position += 1;
+        let start = self.position;
+        let end = self.input[start..]
+            .find(quote)
+            .ok_or_else(|| format!("Unclosed string in format: {}", self.input))?;
+        self.position = start + end + 1;
+        Ok(Expr::Literal(Value::String(
+            self.input[start..start + end].to_string(),
+        )))
+    }
+
+    fn number(&mut self) -> Result<Expr, String> {
+        let start = self.position;
+        self.position += 1;
+        while let Some(c) = self.peek() {
+            if c.is_ascii_digit() || c == '.' {
+                self.position += 1;
+            } else {
+                break;
+            }
+        }
+        let text = &self.input[start..self.position];
+        let number: f64 = text
+            .parse()
+            .map_err(|_| format!("Invalid number {text} in format"))?;
+        Ok(Expr::Literal(Value::from(number)))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input[self.position..].chars().next()
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(expected) {
+            self.position += expected.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.peek().filter(|c| c.is_whitespace()) {
+            self.position += c.len_utf8();
+        }
+    }
+}
+
+fn evaluate(expr: &Expr, values: &Map<String, Value>) -> Result<Value, String> {
+    match expr {
+        Expr::Key(key) => Ok(values.get(key).cloned().unwrap_or(Value::Null)),
+        Expr::Literal(value) => Ok(value.clone()),
+        Expr::Call(name, args) => {
+            let args = args
+                .iter()
+                .map(|arg| evaluate(arg, values))
+                .collect::<Result<Vec<_>, _>>()?;
+            call(name, &args)
+        }
+    }
+}
+
+fn call(name: &str, args: &[Value]) -> Result<Value, String> {
+    let arity = |expected: usize| {
+        if args.len() == expected {
+            Ok(())
+        } else {
+            Err(format!(
+                "{name}() takes {expected} arguments, got {}",
+                args.len()
+            ))
+        }
+    };
+
+    match name {
+        "duration" => {
+            arity(1)?;
+            Ok(Value::String(match args[0].as_f64() {
+                Some(seconds) => format_duration(seconds),
+                None => String::new(),
+            }))
+        }
+        "trunc" => {
+            arity(2)?;
+            let text = display(&args[0]);
+            let length = args[1]
+                .as_f64()
+                .ok_or("trunc() takes a length as second argument")?
+                as usize;
+            if text.chars().count() <= length {
+                Ok(Value::String(text))
+            } else {
+                let mut truncated: String = text.chars().take(length.saturating_sub(1)).collect();
+                truncated.push('…');
+                Ok(Value::String(truncated))
+            }
+        }
+        "uc" => {
+            arity(1)?;
+            Ok(Value::String(display(&args[0]).to_uppercase()))
+        }
+        "lc" => {
+            arity(1)?;
+            Ok(Value::String(display(&args[0]).to_lowercase()))
+        }
+        "default" => {
+            arity(2)?;
+            if display(&args[0]).is_empty() {
+                Ok(args[1].clone())
+            } else {
+                Ok(args[0].clone())
+            }
+        }
+        "markup_escape" => {
+            arity(1)?;
+            Ok(Value::String(
+                display(&args[0])
+                    .replace('&', "&amp;")
+                    .replace('<', "&lt;")
+                    .replace('>', "&gt;"),
+            ))
+        }
+        _ => Err(format!("Unknown format function {name}()")),
+    }
+}
+
+/// Formats seconds as `m:ss`, or `h:mm:ss` from an hour up.
+pub fn format_duration(seconds: f64) -> String {
+    let total = seconds.max(0.0) as u64;
+    let (hours, minutes, seconds) = (total / 3600, total / 60 % 60, total % 60);
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{seconds:02}")
+    } else {
+        format!("{minutes}:{seconds:02}")
+    }
+}
+
+fn display(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(text) => text.clone(),
+        Value::Number(number) => match number.as_f64() {
+            Some(n) if n.fract() == 0.0 => format!("{}", n as i64),
+            Some(n) => format!("{n:.2}"),
+            None => number.to_string(),
+        },
+        Value::Array(items) => items.iter().map(display).collect::<Vec<_>>().join(", "),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn render(template: &str) -> Result<String, String> {
+        let Value::Object(values) = json!({
+            "title": "Song",
+            "duration": 95.0,
+            "xesam:artist": ["A", "B"],
+            "mpris:length": 95_000_000,
+        }) else {
+            unreachable!()
+        };
+        Template::parse(template)?.render(&values)
+    }
+
+    #[test]
+    fn renders_keys_helpers_and_pipes() {
+        assert_eq!(
+            render("{{ title|uc }} [{{duration(duration)}}]").unwrap(),
+            "SONG [1:35]"
+        );
+        assert_eq!(render("{{ trunc(title, 3) }}").unwrap(), "So…");
+        assert_eq!(
+            render("{{ artist|default('Unknown') }}").unwrap(),
+            "Unknown"
+        );
+        assert_eq!(render("{{xesam:artist}}").unwrap(), "A, B");
+        assert_eq!(render("{{ mpris:length }}").unwrap(), "95000000");
+        assert_eq!(render("{{xesam:album}}").unwrap(), "");
+    }
+
+    #[test]
+    fn string_literals_may_hold_braces() {
+        assert_eq!(render("{{ default(artist, '}}') }}!").unwrap(), "}}!");
+        assert_eq!(render(r#"{{ "{{x}}" }}"#).unwrap(), "{{x}}");
+        assert!(render("{{ 'unclosed }}").is_err());
+    }
+
+    #[test]
+    fn any_whitespace_separates() {
+        assert_eq!(render("{{\u{a0}title\u{3000}}}").unwrap(), "Song");
+        assert_eq!(render("{{ title |\u{2003}lc }}").unwrap(), "song");
+    }
+
+    #[test]
+    fn invalid_templates_are_errors() {
+        for template in [
+            "{{ title",
+            "{{ }}",
+            "{{ title title }}",
+            "{{ trunc(title 3) }}",
+        ] {
+            assert!(render(template).is_err(), "{template:?} was accepted");
+        }
+        assert_eq!(
+            render("{{ nope(title) }}"),
+            Err("Unknown format function nope()".to_string())
+        );
+    }
+}
@@ -1,114 +1,529 @@
-#[inline(always)]
-pub fn get_reqwest_client() -> reqwest::Result<reqwest::blocking::Client> {
-    let user_agent: String = format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+pub mod cli;
+pub mod follow;
+pub mod format;
+pub mod profile;
+pub mod tls;
+pub mod tui;
 
-    let reqwest_client = reqwest::blocking::Client::builder()
-        .timeout(std::time::Duration::from_secs(1))
-        .user_agent(user_agent)
-        .build()?;
+use std::time::Duration;
 
-    Ok(reqwest_client)
+/// Port the server listens on unless told otherwise.
+pub const DEFAULT_PORT: u16 = 65420;
+/// How long a request may take unless `--timeout` says otherwise.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+/// Longest silence on a streamed response, twice the keep-alive interval of `/events`.
+pub const STREAM_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum OSMediaMoteError {
+    Usage(String),
+    InvalidHost(String),
+    InvalidFormat(String),
+    /// The config file holding the profiles cannot be read or written.
+    InvalidConfig(String),
+    InvalidResponse(String),
+    /// The server could not be connected to or did not answer in time.
+    Unreachable(reqwest::Error),
+    /// No player is running, or none matches `--player`.
+    NoPlayer(String),
+    /// The player or the server cannot do what was asked.
+    Unsupported(String),
+    /// The server refused the token, or has the endpoint disabled.
+    Unauthorized(String),
+    /// The server's certificate does not match `--fingerprint`.
+    CertificateMismatch(reqwest::Error),
+    /// Any other error status, with the server's message.
+    ServerError(reqwest::StatusCode, String),
+    ReqwestError(reqwest::Error),
+    /// Drawing or reading keys in `tui` failed.
+    Terminal(std::io::Error),
 }
 
-#[inline(always)]
-pub fn reqwest_get(url: &str) -> reqwest::Result<reqwest::blocking::Response> {
-    let reqwest_client = get_reqwest_client()?;
+impl std::fmt::Display for OSMediaMoteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OSMediaMoteError::Usage(message) => write!(f, "{message}"),
+            OSMediaMoteError::InvalidHost(host) => write!(f, "Provided host {host} is invalid"),
+            OSMediaMoteError::InvalidFormat(err) => write!(f, "{err}"),
+            OSMediaMoteError::InvalidConfig(message) => write!(f, "Invalid config: {message}"),
+            OSMediaMoteError::InvalidResponse(response) => {
+                write!(f, "Unexpected response from the server: {response}")
+            }
+            OSMediaMoteError::Unreachable(err) => {
+                let server = err.url().map(server_name).unwrap_or_default();
+                if err.is_timeout() {
+                    write!(
+                        f,
+                        "The server {server} did not answer in time, see --timeout"
+                    )
+                } else {
+                    write!(f, "Could not reach {server}, is the server running?")
+                }
+            }
+            OSMediaMoteError::NoPlayer(message) => write!(f, "{message}"),
+            OSMediaMoteError::Unsupported(message) => write!(f, "{message}"),
+            OSMediaMoteError::Unauthorized(message) => write!(f, "Not allowed: {message}"),
+            OSMediaMoteError::CertificateMismatch(err) => {
+                let server = err.url().map(server_name).unwrap_or_default();
+                write!(
+                    f,
+                    "The certificate of {server} does not match the fingerprint, nothing was sent"
+                )
+            }
+            OSMediaMoteError::ServerError(status, message) => {
+                write!(f, "The server failed with {status}: {message}")
+            }
+            OSMediaMoteError::ReqwestError(err) => write!(f, "Request failed: {err}"),
+            OSMediaMoteError::Terminal(err) => write!(f, "Terminal error: {err}"),
+        }
+    }
+}
 
-    let request = reqwest_client.get(url).build()?;
-    let response = reqwest_client.execute(request)?;
+impl OSMediaMoteError {
+    /// Maps an error status of the server to the error it stands for.
+    fn from_status(status: reqwest::StatusCode, message: String) -> Self {
+        use reqwest::StatusCode;
 
-    Ok(response)
-}
+        let message = message.trim().to_string();
+        match status {
+            StatusCode::BAD_REQUEST => OSMediaMoteError::Usage(message),
+            StatusCode::UNAUTHORIZED => OSMediaMoteError::Unauthorized(format!(
+                "{message}, pass the admin token with --token or OSMEDIAMOTE_TOKEN"
+            )),
+            StatusCode::FORBIDDEN => OSMediaMoteError::Unauthorized(message),
+            // Unknown endpoints answer without a body, a missing player says so.
+            StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED if message.is_empty() => {
+                OSMediaMoteError::Unsupported(
+                    "The server does not support this, it may need an update".to_string(),
+                )
+            }
+            StatusCode::NOT_FOUND => OSMediaMoteError::NoPlayer(message),
+            StatusCode::NOT_IMPLEMENTED => OSMediaMoteError::Unsupported(message),
+            _ => OSMediaMoteError::ServerError(status, message),
+        }
+    }
 
-#[inline(always)]
-pub fn reqwest_put(url: &str) -> reqwest::Result<reqwest::blocking::Response> {
-    let reqwest_client = get_reqwest_client()?;
+    /// Stable name of the error in `--json` output.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            OSMediaMoteError::Usage(_) => "invalid_usage",
+            OSMediaMoteError::InvalidHost(_) => "invalid_host",
+            OSMediaMoteError::InvalidFormat(_) => "invalid_format",
+            OSMediaMoteError::InvalidConfig(_) => "invalid_config",
+            OSMediaMoteError::InvalidResponse(_) => "invalid_response",
+            OSMediaMoteError::Unreachable(_) => "unreachable",
+            OSMediaMoteError::NoPlayer(_) => "no_player",
+            OSMediaMoteError::Unsupported(_) => "unsupported",
+            OSMediaMoteError::Unauthorized(_) => "unauthorized",
+            OSMediaMoteError::CertificateMismatch(_) => "certificate_mismatch",
+            OSMediaMoteError::ServerError(..) => "server_error",
+            OSMediaMoteError::ReqwestError(_) => "request_failed",
+            OSMediaMoteError::Terminal(_) => "terminal",
+        }
+    }
 
-    let request = reqwest_client.put(url).build()?;
-    let response = reqwest_client.execute(request)?;
+    /// Exit code of the process, see [`exit_code`].
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            OSMediaMoteError::Usage(_)
+            | OSMediaMoteError::InvalidHost(_)
+            | OSMediaMoteError::InvalidFormat(_)
+            | OSMediaMoteError::InvalidConfig(_) => exit_code::USAGE,
+            OSMediaMoteError::Unreachable(_) => exit_code::UNREACHABLE,
+            OSMediaMoteError::NoPlayer(_) => exit_code::NO_PLAYER,
+            OSMediaMoteError::Unsupported(_) => exit_code::UNSUPPORTED,
+            OSMediaMoteError::Unauthorized(_) | OSMediaMoteError::CertificateMismatch(_) => {
+                exit_code::UNAUTHORIZED
+            }
+            OSMediaMoteError::InvalidResponse(_)
+            | OSMediaMoteError::ServerError(..)
+            | OSMediaMoteError::ReqwestError(_)
+            | OSMediaMoteError::Terminal(_) => exit_code::FAILURE,
+        }
+    }
 
-    Ok(response)
+    /// The error as `{"error": {"kind": ..., "message": ...}}`.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "error": {
+                "kind": self.kind(),
+                "message": self.to_string(),
+            }
+        })
+    }
 }
 
-#[derive(Debug)]
-pub enum OSMediaMoteError {
-    InvalidOption(String),
-    InvalidOptionsStructure,
-    ReqwestError(reqwest::Error),
+impl From<reqwest::Error> for OSMediaMoteError {
+    fn from(err: reqwest::Error) -> Self {
+        if tls::is_mismatch(&err) {
+            return OSMediaMoteError::CertificateMismatch(err);
+        }
+        // Connections dropped before an answer, e.g. reset by a firewall, count as well.
+        let io_failure = err.is_request() && {
+            let mut source = std::error::Error::source(&err);
+            while let Some(inner) = source.filter(|inner| !inner.is::<std::io::Error>()) {
+                source = inner.source();
+            }
+            source.is_some()
+        };
+        if err.is_connect() || err.is_timeout() || io_failure {
+            OSMediaMoteError::Unreachable(err)
+        } else {
+            OSMediaMoteError::ReqwestError(err)
+        }
+    }
+}
+
+/// Exit codes, so scripts can tell failures apart.
+pub mod exit_code {
+    /// Any failure without a code of its own.
+    pub const FAILURE: i32 = 1;
+    /// Bad arguments, the code clap exits with too.
+    pub const USAGE: i32 = 2;
+    pub const UNREACHABLE: i32 = 3;
+    pub const NO_PLAYER: i32 = 4;
+    pub const UNSUPPORTED: i32 = 5;
+    /// The token was refused, or the certificate does not match `--fingerprint`.
+    pub const UNAUTHORIZED: i32 = 6;
+}
+
+/// `host[:port]` of a URL, for messages.
+fn server_name(url: &url::Url) -> String {
+    match (url.host_str(), url.port_or_known_default()) {
+        (Some(host), Some(port)) => format!("{host}:{port}"),
+        _ => url.to_string(),
+    }
 }
 
-#[derive(PartialEq)]
-pub enum ProgramOption {
-    IP(String),
-    Play,
-    Pause,
-    PlayPause,
-    Next,
-    Previous,
-    Status,
-    Position,
-    SetPosition(u64),
-    Metadata,
-    PrintHelp,
+/// A server and the settings every request to it is made with.
+///
+/// The HTTP clients are built once, as the one pinning a certificate is costly to set up.
+#[derive(Debug, Clone)]
+pub struct Server {
+    pub base: url::Url,
+    /// Player to control instead of the one the server picks, sent as `?player=`.
+    pub player: Option<String>,
+    /// Admin token, sent as `Authorization: Bearer`.
+    pub token: Option<String>,
+    timeout: Duration,
+    /// Gives up on requests after `timeout`.
+    client: reqwest::blocking::Client,
+    /// Gives up after [`STREAM_TIMEOUT`] without data, for responses that stream for as long
+    /// as they like.
+    streaming_client: reqwest::blocking::Client,
 }
 
-pub fn process_args() -> Result<Vec<ProgramOption>, OSMediaMoteError> {
-    let mut options = vec![];
-    let mut args: Vec<String> = std::env::args().skip(1).collect();
-    args.reverse();
-
-    let last_arg = args
-        .pop()
-        .ok_or(OSMediaMoteError::InvalidOptionsStructure)?;
-    let ip = last_arg;
-    if ip.chars().filter(|&c| c == '.').count() != 3 && ip != "--help" {
-        return Err(OSMediaMoteError::InvalidOptionsStructure);
-    }
-    if ip != "--help" {
-        options.push(ProgramOption::IP(ip));
-    }
-    for arg in args {
-        let arg = match arg.as_str() {
-            "--help" => Ok(ProgramOption::PrintHelp),
-            "play" => Ok(ProgramOption::Play),
-            "pause" => Ok(ProgramOption::Pause),
-            "play-pause" => Ok(ProgramOption::PlayPause),
-            "next" => Ok(ProgramOption::Next),
-            "previous" => Ok(ProgramOption::Previous),
-            "status" => Ok(ProgramOption::Status),
-            "position" => Ok(ProgramOption::Position),
-            pos_with_sec if arg.starts_with("position=") => {
-                let sec = pos_with_sec
-                    .replace("position=", "")
-                    .trim()
-                    .parse()
-                    .map_err(|_| OSMediaMoteError::InvalidOption(arg))?;
-                Ok(ProgramOption::SetPosition(sec))
+impl Server {
+    /// A server whose requests give up after `timeout`. With a `fingerprint`, the certificate
+    /// is trusted instead of the system's authorities, for `https` servers.
+    pub fn new(
+        base: url::Url,
+        fingerprint: Option<tls::Fingerprint>,
+        timeout: Duration,
+    ) -> Result<Server, OSMediaMoteError> {
+        // Shared by both clients, so the certificate verifier is only set up once.
+        let tls = fingerprint.map(tls::pinned_config);
+        let client = |request_timeout: Duration| {
+            let user_agent = format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+            let mut client = reqwest::blocking::Client::builder()
+                .timeout(request_timeout)
+                .connect_timeout(timeout)
+                .user_agent(user_agent);
+            if let Some(tls) = &tls {
+                client = client.use_preconfigured_tls(tls.clone());
             }
-            "metadata" => Ok(ProgramOption::Metadata),
-            _ => Err(OSMediaMoteError::InvalidOption(arg)),
+            client.build()
+        };
+
+        Ok(Server {
+            base,
+            player: None,
+            token: None,
+            timeout,
+            client: client(timeout)?,
+            streaming_client: client(STREAM_TIMEOUT)?,
+        })
+    }
+
+    /// How long a request may take.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// URL of the endpoint at `path`.
+    pub fn endpoint(&self, path: &str) -> url::Url {
+        let mut url = self
+            .base
+            .join(path)
+            .expect("endpoint paths are valid relative URLs");
+        if let Some(player) = &self.player {
+            url.query_pairs_mut().append_pair("player", player);
+        }
+        url
+    }
+
+    /// Builds a request to `path`. When `streaming`, the time limit is on each read rather than
+    /// the whole response, see [`STREAM_TIMEOUT`].
+    pub fn request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        streaming: bool,
+    ) -> reqwest::blocking::RequestBuilder {
+        let client = if streaming {
+            &self.streaming_client
+        } else {
+            &self.client
         };
-        options.push(arg?);
+        let mut request = client.request(method, self.endpoint(path));
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        request
+    }
+
+    fn send(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+    ) -> Result<reqwest::blocking::Response, OSMediaMoteError> {
+        let response = self.request(method, path, false).send()?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(OSMediaMoteError::from_status(status, response.text()?));
+        }
+        Ok(response)
+    }
+
+    pub fn get(&self, path: &str) -> Result<String, OSMediaMoteError> {
+        Ok(self.send(reqwest::Method::GET, path)?.text()?)
+    }
+
+    pub fn put(&self, path: &str) -> Result<String, OSMediaMoteError> {
+        Ok(self.send(reqwest::Method::PUT, path)?.text()?)
+    }
+
+    pub fn post(&self, path: &str) -> Result<String, OSMediaMoteError> {
+        Ok(self.send(reqwest::Method::POST, path)?.text()?)
     }
 
-    if options.len() != 2 && (options.contains(&ProgramOption::PrintHelp) && options.len() != 1) {
-        return Err(OSMediaMoteError::InvalidOptionsStructure);
+    /// Fetches an endpoint answering with a plain number, such as `/duration`.
+    fn get_number(&self, path: &str) -> Result<f64, OSMediaMoteError> {
+        let text = self.get(path)?;
+        text.trim()
+            .parse()
+            .map_err(|_| OSMediaMoteError::InvalidResponse(text))
     }
 
-    Ok(options)
+    /// Fetches `/status` as the values `--format` templates can use.
+    ///
+    /// Besides the server's fields, capabilities and the track's MPRIS metadata are flattened in
+    /// and `status` is `Playing` or `Paused` like in playerctl.
+    pub fn status(&self) -> Result<serde_json::Map<String, serde_json::Value>, OSMediaMoteError> {
+        let text = self.get("status")?;
+        let values =
+            serde_json::from_str(&text).map_err(|_| OSMediaMoteError::InvalidResponse(text))?;
+
+        Ok(status_values(values))
+    }
+
+    /// Seeks as `position OFFSET` asks, percentages being taken of the track's duration.
+    ///
+    /// Relative seeks use `/seek`, or read the position and set it on servers without it.
+    pub fn seek(&self, seek: cli::Seek) -> Result<(), OSMediaMoteError> {
+        let seconds = match seek.amount {
+            cli::SeekAmount::Seconds(seconds) => seconds,
+            cli::SeekAmount::Percent(percent) => self.get_number("duration")? * percent / 100.0,
+        };
+
+        let target = if seek.relative {
+            match self.put(&format!("seek/{}", seconds.round() as i64)) {
+                Err(OSMediaMoteError::Unsupported(_)) => self.get_number("position")? + seconds,
+                result => return result.map(|_| ()),
+            }
+        } else {
+            seconds
+        };
+
+        self.put(&format!("position/{}", target.max(0.0).round() as u64))?;
+        Ok(())
+    }
+
+    /// The player's volume, from 0 to 1.
+    pub fn volume(&self) -> Result<f64, OSMediaMoteError> {
+        self.get_number("volume")
+    }
+
+    pub fn set_volume(&self, volume: f64) -> Result<(), OSMediaMoteError> {
+        self.put(&format!("volume/{}", volume.clamp(0.0, 1.0)))?;
+        Ok(())
+    }
+
+    /// Fetches `/art`, the cover of the current track as image bytes.
+    pub fn art(&self) -> Result<Vec<u8>, OSMediaMoteError> {
+        Ok(self.send(reqwest::Method::GET, "art")?.bytes()?.to_vec())
+    }
+
+    /// Fetches `/players`, the players the server can see.
+    pub fn players(&self) -> Result<Vec<serde_json::Value>, OSMediaMoteError> {
+        let text = self.get("players")?;
+        serde_json::from_str(&text).map_err(|_| OSMediaMoteError::InvalidResponse(text))
+    }
+}
+
+/// Builds the server's base URL from `HOST[:PORT]` or a full `http(s)://` URL.
+///
+/// Hosts may be names, IPv4 or IPv6 addresses, the latter optionally in brackets. `port`
+/// overrides the port given in `host`, which defaults to [`DEFAULT_PORT`].
+pub fn parse_base_url(host: &str, port: Option<u16>) -> Result<url::Url, OSMediaMoteError> {
+    let invalid = || OSMediaMoteError::InvalidHost(host.to_string());
+
+    let mut url = if host.contains("://") {
+        url::Url::parse(host).map_err(|_| invalid())?
+    } else {
+        let authority = if host.matches(':').count() > 1 && !host.starts_with('[') {
+            // A bare IPv6 address, which cannot carry a port.
+            format!("[{host}]")
+        } else {
+            host.to_string()
+        };
+        let mut url = url::Url::parse(&format!("http://{authority}")).map_err(|_| invalid())?;
+        if url.port().is_none() {
+            url.set_port(Some(DEFAULT_PORT)).map_err(|_| invalid())?;
+        }
+        url
+    };
+
+    if !matches!(url.scheme(), "http" | "https") || url.host().is_none() {
+        return Err(invalid());
+    }
+    if port.is_some() {
+        url.set_port(port).map_err(|_| invalid())?;
+    }
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    url.set_query(None);
+    url.set_fragment(None);
+
+    Ok(url)
+}
+
+/// Turns a `/status` body into template values, see [`Server::status`].
+pub fn status_values(
+    mut values: serde_json::Map<String, serde_json::Value>,
+) -> serde_json::Map<String, serde_json::Value> {
+    if let Some(serde_json::Value::Object(capabilities)) = values.remove("capabilities") {
+        values.extend(capabilities);
+    }
+    // MPRIS keys such as `xesam:title` cannot clash with the status fields.
+    if let Some(serde_json::Value::Object(metadata)) = values.remove("metadata") {
+        values.extend(metadata);
+    }
+    let playing = values.get("is_playing").and_then(|v| v.as_bool()) == Some(true);
+    values.insert(
+        "status".to_string(),
+        if playing { "Playing" } else { "Paused" }.into(),
+    );
+
+    values
+}
+
+/// `--json` output of `status`, `position` or `metadata`, picked from [`Server::status`] values.
+///
+/// Every field is always present, `null` when the server did not report it.
+pub fn json_output(
+    command: &cli::Command,
+    values: &serde_json::Map<String, serde_json::Value>,
+) -> serde_json::Value {
+    let keys: &[&str] = match command {
+        cli::Command::Status => &["status", "is_playing", "player"],
+        cli::Command::Position { .. } => &["position", "duration", "rate", "is_playing"],
+        _ => &["player", "title", "artist", "album", "duration"],
+    };
+    keys.iter()
+        .map(|key| {
+            (
+                key.to_string(),
+                values.get(*key).cloned().unwrap_or_default(),
+            )
+        })
+        .collect::<serde_json::Map<_, _>>()
+        .into()
 }
 
-pub fn print_help() {
-    println!("Usage: {} IP COMMAND", env!("CARGO_PKG_NAME"));
-    println!("       {} --help", env!("CARGO_PKG_NAME"));
-    println!("Commands:");
-    println!("\t play");
-    println!("\t pause");
-    println!("\t play-pause");
-    println!("\t next");
-    println!("\t previous");
-    println!("\t status");
-    println!("\t position");
-    println!("\t position=[OFFSET]");
-    println!("\t metadata");
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base_url(host: &str, port: Option<u16>) -> String {
+        parse_base_url(host, port).unwrap().to_string()
+    }
+
+    #[test]
+    fn hosts_get_the_default_port() {
+        assert_eq!(base_url("localhost", None), "http://localhost:65420/");
+        assert_eq!(
+            base_url("192.168.1.20:8080", None),
+            "http://192.168.1.20:8080/"
+        );
+        assert_eq!(
+            base_url("192.168.1.20:8080", Some(9000)),
+            "http://192.168.1.20:9000/"
+        );
+    }
+
+    #[test]
+    fn ipv6_addresses_with_and_without_brackets() {
+        assert_eq!(base_url("::1", None), "http://[::1]:65420/");
+        assert_eq!(base_url("fe80::1", Some(8080)), "http://[fe80::1]:8080/");
+        assert_eq!(base_url("[::1]", None), "http://[::1]:65420/");
+        assert_eq!(base_url("[::1]:8080", None), "http://[::1]:8080/");
+        assert_eq!(base_url("https://[::1]/", None), "https://[::1]/");
+    }
+
+    #[test]
+    fn urls_keep_their_path_as_prefix() {
+        assert_eq!(base_url("https://host/mote", None), "https://host/mote/");
+        assert_eq!(
+            base_url("http://host:8080/mote/?x=1#top", None),
+            "http://host:8080/mote/"
+        );
+        assert_eq!(
+            base_url("https://host/mote/", Some(8443)),
+            "https://host:8443/mote/"
+        );
+
+        let server = Server::new(
+            parse_base_url("https://host/mote", None).unwrap(),
+            None,
+            DEFAULT_TIMEOUT,
+        )
+        .unwrap();
+        assert_eq!(
+            server.endpoint("status").as_str(),
+            "https://host/mote/status"
+        );
+    }
+
+    #[test]
+    fn invalid_hosts_are_refused() {
+        for host in [
+            "",
+            "ftp://host",
+            "http://",
+            "host:port",
+            "host:99999",
+            "file:///tmp",
+        ] {
+            assert!(
+                matches!(
+                    parse_base_url(host, None),
+                    Err(OSMediaMoteError::InvalidHost(_))
+                ),
+                "{host:?} was accepted"
+            );
+        }
+    }
 }
@@ -1,73 +1,322 @@
-use osmediamote_cli::{ProgramOption, print_help, process_args, reqwest_get, reqwest_put};
+use clap::CommandFactory;
+use osmediamote_cli::{
+    OSMediaMoteError, Server,
+    cli::{self, Cli, Command, ProfileAction, Wait},
+    follow,
+    format::Template,
+    json_output,
+    profile::Config,
+    tui,
+};
+use std::cell::OnceCell;
+use std::io::Write;
 
 fn main() {
-    let options = process_args()
-        .map_err(|err| {
-            match err {
-                osmediamote_cli::OSMediaMoteError::InvalidOption(option) => {
-                    eprintln!("Provided option {option} is invalid")
-                }
-                osmediamote_cli::OSMediaMoteError::InvalidOptionsStructure => {
-                    eprintln!("Invalid input")
+    // Known before parsing, so that usage errors are reported as JSON too.
+    let json = std::env::args().any(|arg| arg == "--json");
+
+    let (cli, commands) = match cli::parse(cli::args()) {
+        Ok(parsed) => parsed,
+        Err(err) if json && err.use_stderr() => {
+            report(&OSMediaMoteError::Usage(cli::usage_message(&err)), json)
+        }
+        Err(err) => err.exit(),
+    };
+
+    if cli.follow && (commands.len() > 1 || !cli.command.is_query()) {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--follow only works with a single status, position or metadata command",
+            )
+            .exit();
+    }
+
+    let server = LazyServer::new(&cli);
+    let result = if cli.follow {
+        server.get().and_then(|server| follow_command(&cli, server))
+    } else {
+        commands
+            .iter()
+            .try_for_each(|command| run(&cli, &server, command))
+    };
+    if let Err(err) = result {
+        report(&err, cli.json);
+    }
+}
+
+/// The server the commands talk to, built on first use so that commands like `completions` or
+/// `profile` work without one, and only once for chained commands and scripts.
+struct LazyServer<'a> {
+    cli: &'a Cli,
+    server: OnceCell<Server>,
+}
+
+impl<'a> LazyServer<'a> {
+    fn new(cli: &'a Cli) -> Self {
+        LazyServer {
+            cli,
+            server: OnceCell::new(),
+        }
+    }
+
+    fn get(&self) -> Result<&Server, OSMediaMoteError> {
+        if let Some(server) = self.server.get() {
+            return Ok(server);
+        }
+        let server = self.cli.server()?;
+        Ok(self.server.get_or_init(|| server))
+    }
+}
+
+fn run(cli: &Cli, server: &LazyServer, command: &Command) -> Result<(), OSMediaMoteError> {
+    match command {
+        // Written in one go, clap_complete panics when stdout is closed early, e.g. by `head`.
+        Command::Completions { shell } => {
+            let mut script = vec![];
+            clap_complete::generate(
+                *shell,
+                &mut Cli::command(),
+                env!("CARGO_PKG_NAME"),
+                &mut script,
+            );
+            let _ = std::io::stdout().write_all(&script);
+            return Ok(());
+        }
+        Command::Manpage => {
+            let _ = clap_mangen::Man::new(Cli::command()).render(&mut std::io::stdout());
+            return Ok(());
+        }
+        Command::Wait {
+            until: Wait::Duration(duration),
+        } => {
+            std::thread::sleep(*duration);
+            return Ok(());
+        }
+        Command::Run { file } => return run_script(cli, server, file),
+        Command::Profile { action } => return profile_command(cli, action),
+        _ => {}
+    }
+
+    let server = server.get()?;
+
+    if command.is_query() && (cli.json || cli.format.is_some()) {
+        let values = server.status()?;
+        match &cli.format {
+            _ if cli.json => print_line(json_output(command, &values)),
+            Some(format) => print_line(
+                format
+                    .render(&values)
+                    .map_err(OSMediaMoteError::InvalidFormat)?,
+            ),
+            None => unreachable!(),
+        }
+        return Ok(());
+    }
+
+    match command {
+        Command::Play => {
+            server.get("play")?;
+        }
+        Command::Pause => {
+            server.get("pause")?;
+        }
+        Command::PlayPause => {
+            server.get("play_pause")?;
+        }
+        Command::Next => {
+            server.get("play_next")?;
+        }
+        Command::Previous => {
+            server.get("play_prev")?;
+        }
+        Command::Stop => {
+            server.get("stop")?;
+        }
+        Command::Status => print_line(format!("Playing: {}", server.get("is_playing")?)),
+        Command::Position { offset: None } => print_line(server.get("position")?),
+        Command::Position {
+            offset: Some(offset),
+        } => server.seek(*offset)?,
+        Command::Metadata => {
+            print_line(format!("Title: {}", server.get("title")?));
+            print_line(format!("Duration: {}", server.get("duration")?));
+            print_line(format!("Artist: {}", server.get("artist")?));
+        }
+        Command::Players => {
+            let players = server.players()?;
+            if cli.json {
+                print_line(serde_json::Value::from(players));
+            } else {
+                for player in players {
+                    print_line(player["name"].as_str().unwrap_or_default());
                 }
-                _ => panic!("{:?}", err),
             }
-            print_help();
-            std::process::exit(-1);
-        })
-        .unwrap();
-    if options.contains(&ProgramOption::PrintHelp) {
-        print_help();
-        std::process::exit(-1);
+        }
+        Command::Quit => {
+            server.post("quit")?;
+        }
+        Command::Tui => tui::run(server)?,
+        Command::Wait {
+            until: Wait::TrackChange,
+        } => follow::wait_for_track_change(server)?,
+        Command::Completions { .. }
+        | Command::Manpage
+        | Command::Wait { .. }
+        | Command::Run { .. }
+        | Command::Profile { .. } => unreachable!(),
     }
 
-    let ip = options
-        .iter()
-        .find_map(|o| match o {
-            ProgramOption::IP(ip) => Some(ip.to_owned()),
-            _ => None,
-        })
-        .unwrap();
-
-    for option in options {
-        match option {
-            ProgramOption::IP(_) => (),
-            ProgramOption::Play => {
-                let _ = reqwest_get(&format!("http://{ip}:65420/play")).unwrap();
-            }
-            ProgramOption::Pause => {
-                let _ = reqwest_get(&format!("http://{ip}:65420/pause")).unwrap();
-            }
-            ProgramOption::PlayPause => {
-                let _ = reqwest_get(&format!("http://{ip}:65420/play_pause")).unwrap();
-            }
-            ProgramOption::Next => {
-                let _ = reqwest_get(&format!("http://{ip}:65420/play_next")).unwrap();
-            }
-            ProgramOption::Previous => {
-                let _ = reqwest_get(&format!("http://{ip}:65420/play_prev")).unwrap();
-            }
-            ProgramOption::Status => {
-                let res = reqwest_get(&format!("http://{ip}:65420/is_playing")).unwrap();
-                println!("Playing: {}", res.text().unwrap());
+    Ok(())
+}
+
+fn profile_command(cli: &Cli, action: &ProfileAction) -> Result<(), OSMediaMoteError> {
+    let mut config = Config::load()?;
+    match action {
+        ProfileAction::Add {
+            name,
+            host,
+            default,
+        } => {
+            if name.is_empty() || name.starts_with('@') {
+                return Err(OSMediaMoteError::Usage(format!(
+                    "{name:?} is not a profile name, use it without the @"
+                )));
             }
-            ProgramOption::Position => {
-                let res = reqwest_get(&format!("http://{ip}:65420/position")).unwrap();
-                println!("{}", res.text().unwrap());
+            config.profiles.insert(name.clone(), cli.profile(host)?);
+            if *default || config.default.is_none() {
+                config.default = Some(name.clone());
             }
-            ProgramOption::SetPosition(position) => {
-                let res = reqwest_put(&format!("http://{ip}:65420/position/{position}")).unwrap();
-                println!("{}", res.text().unwrap());
+        }
+        ProfileAction::List => {
+            let default = config.default.as_deref();
+            // Tokens stay in the config file.
+            if cli.json {
+                let profiles: Vec<_> = config
+                    .profiles
+                    .iter()
+                    .map(|(name, profile)| {
+                        serde_json::json!({
+                            "name": name,
+                            "host": profile.host,
+                            "port": profile.port,
+                            "player": profile.player,
+                            "fingerprint": profile.fingerprint,
+                            "has_token": profile.token.is_some(),
+                            "default": default == Some(name.as_str()),
+                        })
+                    })
+                    .collect();
+                print_line(serde_json::Value::from(profiles));
+            } else {
+                for (name, profile) in &config.profiles {
+                    let marker = if default == Some(name.as_str()) {
+                        "*"
+                    } else {
+                        " "
+                    };
+                    let mut line = format!("{marker} {name}\t{}", profile.host);
+                    if let Some(port) = profile.port {
+                        line += &format!(" --port {port}");
+                    }
+                    if let Some(player) = &profile.player {
+                        line += &format!(" --player {player}");
+                    }
+                    if profile.fingerprint.is_some() {
+                        line += " --fingerprint …";
+                    }
+                    if profile.token.is_some() {
+                        line += " --token …";
+                    }
+                    print_line(line);
+                }
             }
-            ProgramOption::Metadata => {
-                let res = reqwest_get(&format!("http://{ip}:65420/title")).unwrap();
-                println!("Title: {}", res.text().unwrap());
-                let res = reqwest_get(&format!("http://{ip}:65420/duration")).unwrap();
-                println!("Duration: {}", res.text().unwrap());
-                let res = reqwest_get(&format!("http://{ip}:65420/artist")).unwrap();
-                println!("Artist: {}", res.text().unwrap());
+            return Ok(());
+        }
+        ProfileAction::Remove { name } => {
+            config.profile(name)?;
+            config.profiles.remove(name);
+            if config.default.as_ref() == Some(name) {
+                config.default = None;
             }
-            ProgramOption::PrintHelp => unreachable!(),
+        }
+        ProfileAction::Default { name } => {
+            config.profile(name)?;
+            config.default = Some(name.clone());
         }
     }
+    config.save()
+}
+
+/// Runs the commands of a script, `-` being stdin. Blank lines and `#` comments are skipped.
+fn run_script(cli: &Cli, server: &LazyServer, file: &str) -> Result<(), OSMediaMoteError> {
+    let script = if file == "-" {
+        std::io::read_to_string(std::io::stdin())
+    } else {
+        std::fs::read_to_string(file)
+    }
+    .map_err(|err| OSMediaMoteError::Usage(format!("Could not read {file}: {err}")))?;
+
+    // Parsed up front, so that a typo does not stop the script halfway.
+    let mut commands = vec![];
+    for (number, line) in script.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let line_commands = cli::parse_line(line).map_err(|err| {
+            OSMediaMoteError::Usage(format!(
+                "{file}:{}: {}",
+                number + 1,
+                cli::usage_message(&err)
+            ))
+        })?;
+        commands.extend(line_commands);
+    }
+
+    commands
+        .iter()
+        .try_for_each(|command| run(cli, server, command))
+}
+
+fn follow_command(cli: &Cli, server: &Server) -> Result<(), OSMediaMoteError> {
+    if cli.json {
+        return follow::follow(server, |values| {
+            Ok(match values {
+                Some(values) => json_output(&cli.command, values).to_string(),
+                None => "null".to_string(),
+            })
+        });
+    }
+
+    let format = cli.format.clone().unwrap_or_else(|| {
+        let template = match cli.command {
+            Command::Status => "Playing: {{is_playing}}",
+            Command::Position { .. } => "{{position}}",
+            _ => "{{artist}} - {{title}}",
+        };
+        Template::parse(template).expect("default templates are valid")
+    });
+    follow::follow(server, |values| match values {
+        Some(values) => format
+            .render(values)
+            .map_err(OSMediaMoteError::InvalidFormat),
+        None => Ok(String::new()),
+    })
+}
+
+/// Prints a line on stdout. Unlike `println!`, it does not panic once the reader went away,
+/// e.g. `head` after its first lines.
+fn print_line(line: impl std::fmt::Display) {
+    let _ = writeln!(std::io::stdout(), "{line}");
+}
+
+/// Prints `err` on stderr, as JSON with `--json`, and exits.
+fn report(err: &OSMediaMoteError, json: bool) -> ! {
+    let _ = if json {
+        writeln!(std::io::stderr(), "{}", err.to_json())
+    } else {
+        writeln!(std::io::stderr(), "{err}")
+    };
+    std::process::exit(err.exit_code());
 }
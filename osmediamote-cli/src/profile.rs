@@ -0,0 +1,117 @@
+//! Named servers kept in a config file, used as `@name` in place of HOST.
+//!
+//! The file is `osmediamote-cli/config.toml` in the user's config directory, or the path in
+//! `OSMEDIAMOTE_CONFIG`:
+//!
+//! ```toml
+//! default = "office"
+//!
+//! [profiles.office]
+//! host = "192.168.1.20"
+//! player = "spotify"
+//! ```
+
+use crate::OSMediaMoteError;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Environment variable overriding where the config file is.
+pub const CONFIG_ENV: &str = "OSMEDIAMOTE_CONFIG";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Config {
+    /// Profile used when no server is given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<String>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    /// Anything HOST accepts, such as `192.168.1.20:8080` or `https://host/mote/`.
+    pub host: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// SHA-256 fingerprint of the server's TLS certificate.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub player: Option<String>,
+}
+
+impl Config {
+    /// Where the config file is.
+    pub fn path() -> Result<PathBuf, OSMediaMoteError> {
+        if let Some(path) = std::env::var_os(CONFIG_ENV) {
+            return Ok(PathBuf::from(path));
+        }
+        dirs::config_dir()
+            .map(|dir| dir.join(env!("CARGO_PKG_NAME")).join("config.toml"))
+            .ok_or_else(|| {
+                OSMediaMoteError::InvalidConfig(format!(
+                    "No config directory found, set {CONFIG_ENV}"
+                ))
+            })
+    }
+
+    /// Reads the config file, an empty config when there is none yet.
+    pub fn load() -> Result<Config, OSMediaMoteError> {
+        let path = Config::path()?;
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(err) => {
+                return Err(OSMediaMoteError::InvalidConfig(format!(
+                    "Could not read {}: {err}",
+                    path.display()
+                )));
+            }
+        };
+        toml::from_str(&text).map_err(|err| {
+            OSMediaMoteError::InvalidConfig(format!("{}: {}", path.display(), err.message()))
+        })
+    }
+
+    /// Writes the config file, readable by the user only as it may hold tokens.
+    pub fn save(&self) -> Result<(), OSMediaMoteError> {
+        let path = Config::path()?;
+        let failed = |err: std::io::Error| {
+            OSMediaMoteError::InvalidConfig(format!("Could not write {}: {err}", path.display()))
+        };
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(failed)?;
+        }
+        let text = toml::to_string(self).expect("the config serializes to TOML");
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&path).map_err(failed)?;
+        // `mode` only applies to new files, an existing one may have been readable by others.
+        #[cfg(unix)]
+        file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))
+            .map_err(failed)?;
+        std::io::Write::write_all(&mut file, text.as_bytes()).map_err(failed)
+    }
+
+    /// The profile called `name`.
+    pub fn profile(&self, name: &str) -> Result<&Profile, OSMediaMoteError> {
+        self.profiles.get(name).ok_or_else(|| {
+            OSMediaMoteError::Usage(format!("No profile named {name}, see `profile list`"))
+        })
+    }
+
+    /// The default profile, `None` when there is none.
+    pub fn default_profile(&self) -> Result<Option<&Profile>, OSMediaMoteError> {
+        self.default
+            .as_deref()
+            .map(|name| self.profile(name))
+            .transpose()
+    }
+}
@@ -0,0 +1,205 @@
+//! Certificate pinning for `--fingerprint`: the server's certificate is accepted when its
+//! SHA-256 fingerprint matches, whoever issued it, so self-signed certificates work.
+//!
+//! The check happens during the handshake, before any request is sent, the token included.
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::WebPkiSupportedAlgorithms;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{CertificateError, DigitallySignedStruct, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+/// SHA-256 digest of a DER certificate.
+pub type Fingerprint = [u8; 32];
+
+/// Parses a fingerprint as printed by `openssl x509 -fingerprint -sha256`, hex bytes optionally
+/// separated by colons, with or without the `sha256 Fingerprint=` prefix.
+pub fn parse_fingerprint(value: &str) -> Result<Fingerprint, String> {
+    let invalid = || format!("{value} is not a SHA-256 fingerprint such as AB:CD:…:EF");
+
+    let hex: String = value
+        .rsplit('=')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| *c != ':')
+        .collect();
+    // Checked up front, `from_str_radix` would take a `+` sign.
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+
+    let mut fingerprint = [0; 32];
+    for (byte, pair) in fingerprint.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+    }
+    Ok(fingerprint)
+}
+
+/// Formats a fingerprint like `openssl` does, `AB:CD:…:EF`.
+pub fn format_fingerprint(fingerprint: &Fingerprint) -> String {
+    fingerprint
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// TLS settings that only trust a certificate with `fingerprint`.
+pub fn pinned_config(fingerprint: Fingerprint) -> rustls::ClientConfig {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let verifier = PinnedVerifier {
+        fingerprint,
+        algorithms: provider.signature_verification_algorithms,
+    };
+
+    rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .expect("the ring provider supports the default protocol versions")
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth()
+}
+
+/// Whether `err` comes from a certificate that does not match the fingerprint.
+pub fn is_mismatch(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(rustls::Error::InvalidCertificate(
+            CertificateError::ApplicationVerificationFailure,
+        )) = err.downcast_ref::<rustls::Error>()
+        {
+            return true;
+        }
+        // `io::Error` skips the error it wraps in `source`, which can be another `io::Error`.
+        source = match err.downcast_ref::<std::io::Error>() {
+            Some(io) => io
+                .get_ref()
+                .map(|inner| inner as &(dyn std::error::Error + 'static)),
+            None => err.source(),
+        };
+    }
+    false
+}
+
+#[derive(Debug)]
+struct PinnedVerifier {
+    fingerprint: Fingerprint,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if Sha256::digest(end_entity.as_ref()).as_slice() == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "9F86D081884C7D659A2FEAA0C55AD015A3BF4F1B2B0B822CD15D6C15B0F00A08";
+
+    fn with_colons(hex: &str) -> String {
+        hex.as_bytes()
+            .chunks(2)
+            .map(|pair| std::str::from_utf8(pair).unwrap())
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+
+    fn verify(verifier: &PinnedVerifier, der: &[u8]) -> Result<ServerCertVerified, rustls::Error> {
+        verifier.verify_server_cert(
+            &CertificateDer::from(der.to_vec()),
+            &[],
+            &ServerName::try_from("localhost").unwrap(),
+            &[],
+            UnixTime::now(),
+        )
+    }
+
+    #[test]
+    fn parses_openssl_output_with_or_without_colons() {
+        let expected = parse_fingerprint(HEX).unwrap();
+        assert_eq!(expected, <[u8; 32]>::from(Sha256::digest(b"test")));
+
+        let colons = with_colons(HEX);
+        assert_eq!(parse_fingerprint(&colons).unwrap(), expected);
+        assert_eq!(
+            parse_fingerprint(&format!("sha256 Fingerprint={colons}")).unwrap(),
+            expected
+        );
+        assert_eq!(parse_fingerprint(&HEX.to_lowercase()).unwrap(), expected);
+        assert_eq!(format_fingerprint(&expected), colons);
+    }
+
+    #[test]
+    fn refuses_wrong_lengths_and_non_hex() {
+        for value in [
+            "",
+            &HEX[..62],
+            &format!("{HEX}00"),
+            &HEX.replacen('9', "G", 1),
+            &HEX.replacen("9F", "+F", 1),
+            &HEX.replacen("9F", "é", 1),
+        ] {
+            assert!(parse_fingerprint(value).is_err(), "{value} was accepted");
+        }
+    }
+
+    #[test]
+    fn verifier_only_accepts_the_pinned_certificate() {
+        let verifier = PinnedVerifier {
+            fingerprint: Sha256::digest(b"pinned").into(),
+            algorithms: rustls::crypto::ring::default_provider().signature_verification_algorithms,
+        };
+
+        assert!(verify(&verifier, b"pinned").is_ok());
+        let err = verify(&verifier, b"other").unwrap_err();
+        assert_eq!(
+            err,
+            rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure)
+        );
+        assert!(is_mismatch(&err));
+        assert!(!is_mismatch(&rustls::Error::InvalidCertificate(
+            CertificateError::Expired
+        )));
+    }
+}
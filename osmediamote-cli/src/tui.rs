@@ -0,0 +1,489 @@
+//! `tui`: an interactive remote with the current track, a live progress bar, the cover and key
+//! bindings for the usual commands.
+//!
+//! Updates come from the same watcher as `--follow`. The cover is drawn with the kitty or iTerm
+//! graphics protocols, or sixel when built with the `sixel` feature, and with half block
+//! characters on other terminals.
+
+use crate::cli::{Seek, SeekAmount};
+use crate::follow::{self, Update};
+use crate::format::format_duration;
+use crate::{OSMediaMoteError, Server};
+use image::DynamicImage;
+use image::imageops::FilterType;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Clear, LineGauge, List, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame, symbols};
+use serde_json::{Map, Value};
+use std::io::{IsTerminal, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant};
+
+/// How long to wait for a key before redrawing, so the progress bar keeps moving.
+const FRAME: Duration = Duration::from_millis(250);
+const SEEK_STEP: f64 = 5.0;
+const VOLUME_STEP: f64 = 0.05;
+
+const KEYS: &str =
+    "space play/pause  ←/→ seek  n/p next/previous  s stop  +/- volume  l players  q quit";
+
+/// Runs the remote until `q` is pressed.
+pub fn run(server: &Server) -> Result<(), OSMediaMoteError> {
+    if !std::io::stdout().is_terminal() {
+        return Err(OSMediaMoteError::Usage(
+            "tui needs a terminal to draw on".to_string(),
+        ));
+    }
+
+    // Asked before the terminal is taken over, as the kitty check reads the answer from stdin.
+    let graphics = graphics_support();
+    let mut terminal = ratatui::try_init().map_err(OSMediaMoteError::Terminal)?;
+    let mut app = App::new(server.clone(), graphics);
+    let result = app.run(&mut terminal);
+    app.hide_cover();
+    ratatui::restore();
+    result
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Graphics {
+    /// Half block characters, two pixels per cell.
+    Blocks,
+    Kitty,
+    /// iTerm or sixel, drawn into the cells like text.
+    Inline,
+}
+
+fn graphics_support() -> Graphics {
+    #[cfg(feature = "sixel")]
+    if viuer::is_sixel_supported() {
+        return Graphics::Inline;
+    }
+    if viuer::is_iterm_supported() {
+        Graphics::Inline
+    } else if viuer::get_kitty_support() != viuer::KittySupport::None {
+        Graphics::Kitty
+    } else {
+        Graphics::Blocks
+    }
+}
+
+struct Cover {
+    image: DynamicImage,
+    /// The image scaled for block characters, and the area it was scaled for.
+    scaled: Option<(Rect, image::RgbImage)>,
+}
+
+/// What is fetched besides the status when the track changes.
+struct TrackDetails {
+    track: Option<[Option<Value>; 4]>,
+    cover: Option<DynamicImage>,
+    volume: Option<f64>,
+}
+
+struct App {
+    server: Server,
+    updates: Receiver<Update>,
+    /// Details fetched in the background, so a slow server does not freeze the remote.
+    details: (Sender<TrackDetails>, Receiver<TrackDetails>),
+    graphics: Graphics,
+    /// `None` until the server answers, then the status, `None` itself while no player runs.
+    status: Option<Option<(Map<String, Value>, Instant)>>,
+    track: Option<[Option<Value>; 4]>,
+    volume: Option<f64>,
+    cover: Option<Cover>,
+    /// Where the cover was last drawn with a graphics protocol.
+    printed: Option<Rect>,
+    cover_area: Rect,
+    /// The player list, while it is open.
+    players: Option<(Vec<Option<String>>, ListState)>,
+    message: Option<String>,
+}
+
+impl App {
+    fn new(server: Server, graphics: Graphics) -> App {
+        App {
+            updates: follow::updates(&server),
+            details: mpsc::channel(),
+            server,
+            graphics,
+            status: None,
+            track: None,
+            volume: None,
+            cover: None,
+            printed: None,
+            cover_area: Rect::default(),
+            players: None,
+            message: None,
+        }
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<(), OSMediaMoteError> {
+        loop {
+            while let Ok(update) = self.updates.try_recv() {
+                self.update(update);
+            }
+            while let Ok(details) = self.details.1.try_recv() {
+                // Details of a track that already ended are dropped.
+                if details.track == self.track {
+                    self.cover = details.cover.map(|image| Cover {
+                        image,
+                        scaled: None,
+                    });
+                    self.volume = details.volume;
+                }
+            }
+
+            terminal
+                .draw(|frame| self.draw(frame))
+                .map_err(OSMediaMoteError::Terminal)?;
+            self.print_cover();
+
+            if !event::poll(FRAME).map_err(OSMediaMoteError::Terminal)? {
+                continue;
+            }
+            if let Event::Key(key) = event::read().map_err(OSMediaMoteError::Terminal)?
+                && key.kind == KeyEventKind::Press
+            {
+                let quit =
+                    key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                if quit || !self.key(key.code) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn update(&mut self, update: Update) {
+        let values = match update {
+            Update::Status(values) => values,
+            Update::Disconnected(_) => {
+                self.status = None;
+                return;
+            }
+        };
+
+        let track = values.as_ref().map(follow::track);
+        if track != self.track {
+            self.track = track;
+            self.cover = None;
+            self.hide_cover();
+            self.fetch_details(values.as_ref());
+        }
+        self.status = Some(values.map(|values| (values, Instant::now())));
+    }
+
+    /// Fetches the cover and volume of the track playing with `values` on another thread.
+    fn fetch_details(&mut self, values: Option<&Map<String, Value>>) {
+        let Some(values) = values else {
+            self.volume = None;
+            return;
+        };
+        let has_volume = values.get("has_volume").and_then(Value::as_bool) != Some(false);
+        let server = self.server.clone();
+        let sender = self.details.0.clone();
+        let track = self.track.clone();
+        std::thread::spawn(move || {
+            let cover = server
+                .art()
+                .ok()
+                .and_then(|bytes| image::load_from_memory(&bytes).ok());
+            let volume = has_volume.then(|| server.volume().ok()).flatten();
+            let _ = sender.send(TrackDetails {
+                track,
+                cover,
+                volume,
+            });
+        });
+    }
+
+    /// Handles a key, returns `false` to quit.
+    fn key(&mut self, code: KeyCode) -> bool {
+        self.message = None;
+        if self.players.is_some() {
+            self.player_key(code);
+            return true;
+        }
+
+        let result = match code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char(' ') => self.server.get("play_pause").map(drop),
+            KeyCode::Char('n') => self.server.get("play_next").map(drop),
+            KeyCode::Char('p') => self.server.get("play_prev").map(drop),
+            KeyCode::Char('s') => self.server.get("stop").map(drop),
+            KeyCode::Left => self.seek(-SEEK_STEP),
+            KeyCode::Right => self.seek(SEEK_STEP),
+            KeyCode::Char('+' | '=') | KeyCode::Up => self.change_volume(VOLUME_STEP),
+            KeyCode::Char('-') | KeyCode::Down => self.change_volume(-VOLUME_STEP),
+            KeyCode::Char('l') => self.open_players(),
+            _ => Ok(()),
+        };
+        if let Err(err) = result {
+            self.message = Some(err.to_string());
+        }
+        true
+    }
+
+    fn seek(&self, seconds: f64) -> Result<(), OSMediaMoteError> {
+        self.server.seek(Seek {
+            amount: SeekAmount::Seconds(seconds),
+            relative: true,
+        })
+    }
+
+    fn change_volume(&mut self, step: f64) -> Result<(), OSMediaMoteError> {
+        let volume = (self.server.volume()? + step).clamp(0.0, 1.0);
+        self.server.set_volume(volume)?;
+        self.volume = Some(self.server.volume()?);
+        Ok(())
+    }
+
+    fn open_players(&mut self) -> Result<(), OSMediaMoteError> {
+        // `None` lets the server pick, as without `--player`.
+        let names = std::iter::once(None)
+            .chain(
+                self.server
+                    .players()?
+                    .iter()
+                    .map(|player| player["name"].as_str().map(str::to_string)),
+            )
+            .collect::<Vec<_>>();
+        let selected = names
+            .iter()
+            .position(|name| *name == self.server.player)
+            .unwrap_or(0);
+        self.players = Some((names, ListState::default().with_selected(Some(selected))));
+        self.hide_cover();
+        Ok(())
+    }
+
+    fn player_key(&mut self, code: KeyCode) {
+        let Some((names, state)) = &mut self.players else {
+            return;
+        };
+        match code {
+            KeyCode::Up | KeyCode::Char('k') => state.select_previous(),
+            KeyCode::Down | KeyCode::Char('j') => state.select_next(),
+            KeyCode::Enter => {
+                let name = state.selected().and_then(|index| names.get(index)).cloned();
+                if let Some(name) = name
+                    && name != self.server.player
+                {
+                    self.server.player = name;
+                    self.updates = follow::updates(&self.server);
+                    self.status = None;
+                    self.track = None;
+                    self.cover = None;
+                }
+                self.players = None;
+            }
+            KeyCode::Esc | KeyCode::Char('q' | 'l') => self.players = None,
+            _ => {}
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, footer] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+
+        let host = self.server.base.host_str().unwrap_or_default().to_string();
+        let values = match &self.status {
+            Some(Some((values, received))) => Some(follow::advance(values, received.elapsed())),
+            _ => None,
+        };
+        let text = |key: &str| {
+            values
+                .as_ref()
+                .and_then(|values| values.get(key))
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string()
+        };
+        let number = |key: &str| {
+            values
+                .as_ref()
+                .and_then(|values| values.get(key))
+                .and_then(Value::as_f64)
+        };
+
+        let title = match (&self.server.player, text("player")) {
+            (Some(player), _) => format!(" {host} · {player} "),
+            (None, player) if !player.is_empty() => format!(" {host} · {player} "),
+            (None, _) => format!(" {host} "),
+        };
+        let block = Block::bordered().title(title.bold());
+        let inner = block.inner(main);
+        frame.render_widget(block, main);
+
+        let [body, progress] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(inner);
+        // Terminal cells are about twice as high as wide.
+        let cover_width = (body.height * 2).min(body.width / 2);
+        let [cover_area, _, info] = Layout::horizontal([
+            Constraint::Length(cover_width),
+            Constraint::Length(2),
+            Constraint::Min(0),
+        ])
+        .areas(body);
+        self.cover_area = cover_area;
+
+        let lines = match &self.status {
+            None => vec![Line::from(format!("Connecting to {host}…").dim())],
+            Some(None) => vec![Line::from("No player is running".dim())],
+            Some(Some(_)) => {
+                let playing = values
+                    .as_ref()
+                    .and_then(|values| values.get("is_playing"))
+                    .and_then(Value::as_bool)
+                    == Some(true);
+                let state = if playing { "▶ Playing" } else { "⏸ Paused" };
+                let volume = match self.volume {
+                    Some(volume) => format!("Volume {:.0}%", volume * 100.0),
+                    None => "Volume –".to_string(),
+                };
+                vec![
+                    Line::from(text("title").bold()),
+                    Line::from(text("artist")),
+                    Line::from(text("album").dim()),
+                    Line::default(),
+                    Line::from(vec![Span::from(state), Span::from("   "), volume.dim()]),
+                ]
+            }
+        };
+        frame.render_widget(Paragraph::new(lines), info);
+
+        if let (Some(position), Some(duration)) = (number("position"), number("duration")) {
+            let ratio = if duration > 0.0 {
+                (position / duration).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            let gauge = LineGauge::default()
+                .ratio(ratio)
+                .label(format!(
+                    "{} / {}",
+                    format_duration(position),
+                    format_duration(duration)
+                ))
+                .filled_symbol(symbols::line::THICK_HORIZONTAL)
+                .filled_style(Style::new().fg(Color::Cyan))
+                .unfilled_style(Style::new().fg(Color::DarkGray));
+            frame.render_widget(gauge, progress);
+        }
+
+        if self.graphics == Graphics::Blocks
+            && let Some(cover) = &mut self.cover
+        {
+            draw_blocks(cover, cover_area, frame);
+        }
+
+        let footer_line = match &self.message {
+            Some(message) => Line::from(message.as_str().red()),
+            None => Line::from(KEYS.dim()),
+        };
+        frame.render_widget(Paragraph::new(footer_line), footer);
+
+        if let Some((names, state)) = &mut self.players {
+            let items = names
+                .iter()
+                .map(|name| name.as_deref().unwrap_or("Automatic").to_string());
+            let list = List::new(items)
+                .block(Block::bordered().title(" Players "))
+                .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+            let width = names
+                .iter()
+                .map(|name| name.as_deref().map_or(9, str::len) as u16 + 4)
+                .max()
+                .unwrap_or_default()
+                .max(13);
+            let popup = main.centered(
+                Constraint::Length(width),
+                Constraint::Length(names.len() as u16 + 2),
+            );
+            frame.render_widget(Clear, popup);
+            frame.render_stateful_widget(list, popup, state);
+        }
+    }
+
+    /// Draws the cover with a graphics protocol, once per track and layout.
+    fn print_cover(&mut self) {
+        let area = self.cover_area;
+        if self.graphics == Graphics::Blocks
+            || self.players.is_some()
+            || self.printed == Some(area)
+            || area.is_empty()
+        {
+            return;
+        }
+        if self.cover.is_none() {
+            return;
+        }
+
+        self.hide_cover();
+        let Some(cover) = &self.cover else {
+            return;
+        };
+        let config = viuer::Config {
+            x: area.x,
+            y: area.y as i16,
+            width: Some(area.width as u32),
+            height: Some(area.height as u32),
+            absolute_offset: true,
+            restore_cursor: true,
+            ..Default::default()
+        };
+        if viuer::print(&cover.image, &config).is_ok() {
+            self.printed = Some(area);
+        }
+    }
+
+    /// Removes a kitty cover, which would otherwise stay above the text.
+    fn hide_cover(&mut self) {
+        if self.graphics == Graphics::Kitty && self.printed.is_some() {
+            let mut stdout = std::io::stdout();
+            let _ = write!(stdout, "\x1b_Ga=d\x1b\\").and_then(|_| stdout.flush());
+        }
+        self.printed = None;
+    }
+}
+
+/// Draws the cover with `▀` characters, the foreground being the upper pixel and the background
+/// the lower one.
+fn draw_blocks(cover: &mut Cover, area: Rect, frame: &mut Frame) {
+    if area.is_empty() {
+        return;
+    }
+    let scaled = match &cover.scaled {
+        Some((scaled_area, scaled)) if *scaled_area == area => scaled,
+        _ => {
+            let scaled = cover
+                .image
+                .resize(
+                    area.width as u32,
+                    area.height as u32 * 2,
+                    FilterType::Triangle,
+                )
+                .to_rgb8();
+            &cover.scaled.insert((area, scaled)).1
+        }
+    };
+
+    let left = area.x + (area.width - scaled.width() as u16) / 2;
+    let top = area.y + (area.height - scaled.height().div_ceil(2) as u16) / 2;
+    let buffer = frame.buffer_mut();
+    for (x, y, pixel) in scaled.enumerate_pixels().filter(|(_, y, _)| y % 2 == 0) {
+        let [r, g, b] = pixel.0;
+        let below = scaled
+            .get_pixel_checked(x, y + 1)
+            .map_or(Color::Reset, |pixel| {
+                let [r, g, b] = pixel.0;
+                Color::Rgb(r, g, b)
+            });
+        if let Some(cell) = buffer.cell_mut((left + x as u16, top + (y / 2) as u16)) {
+            cell.set_char('▀').set_fg(Color::Rgb(r, g, b)).set_bg(below);
+        }
+    }
+}
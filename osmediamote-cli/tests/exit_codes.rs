@@ -0,0 +1,151 @@
+//! Runs the binary on bad input, which must end with one of its exit codes rather than a panic.
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+/// A port nothing listens on, so requests fail straight away.
+const CLOSED_SERVER: &str = "127.0.0.1:1";
+
+const USAGE: i32 = 2;
+const UNREACHABLE: i32 = 3;
+
+fn cli(args: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_osmediamote-cli"));
+    command
+        .args(args)
+        .env("OSMEDIAMOTE_CONFIG", temp_file("missing.toml"))
+        .env_remove("OSMEDIAMOTE_HOST")
+        .env_remove("OSMEDIAMOTE_PLAYER")
+        .env_remove("OSMEDIAMOTE_TOKEN")
+        .env_remove("OSMEDIAMOTE_FINGERPRINT");
+    command
+}
+
+fn run(args: &[&str]) -> Output {
+    cli(args).stdin(Stdio::null()).output().unwrap()
+}
+
+fn temp_file(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "{}-{}-{name}",
+        env!("CARGO_PKG_NAME"),
+        std::process::id()
+    ))
+}
+
+#[track_caller]
+fn assert_exit(args: &[&str], code: i32) {
+    let output = run(args);
+    assert_eq!(
+        output.status.code(),
+        Some(code),
+        "{args:?} printed {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn huge_timeouts_and_durations_are_usage_errors() {
+    assert_exit(&[CLOSED_SERVER, "--timeout", "1e30", "status"], USAGE);
+    assert_exit(&[CLOSED_SERVER, "--timeout", "inf", "status"], USAGE);
+    assert_exit(&[CLOSED_SERVER, "--timeout", "0", "status"], USAGE);
+    assert_exit(&[CLOSED_SERVER, "wait", "99999999999999999999999h"], USAGE);
+    assert_exit(&[CLOSED_SERVER, "wait", "1e400s"], USAGE);
+}
+
+#[test]
+fn bad_offsets_hosts_and_fingerprints_are_usage_errors() {
+    assert_exit(&[CLOSED_SERVER, "position", "1:60"], USAGE);
+    assert_exit(&[CLOSED_SERVER, "position", "1e3"], USAGE);
+    assert_exit(&["http://[::1", "status"], USAGE);
+    assert_exit(&["ftp://host", "status"], USAGE);
+    assert_exit(&["host:99999", "status"], USAGE);
+    assert_exit(&["--fingerprint", "AB:CD", "https://host", "status"], USAGE);
+    assert_exit(
+        &["--fingerprint", &"AB".repeat(32), "http://host", "status"],
+        USAGE,
+    );
+    assert_exit(&["status"], USAGE);
+}
+
+#[test]
+fn formats_parse_or_are_usage_errors() {
+    assert_exit(&[CLOSED_SERVER, "--format", "{{ title", "status"], USAGE);
+    assert_exit(
+        &[CLOSED_SERVER, "--format", "{{ 'title }}", "status"],
+        USAGE,
+    );
+    assert_exit(&[CLOSED_SERVER, "--format", "{{ -}}", "status"], USAGE);
+    // Valid, so the command goes on to the server.
+    assert_exit(
+        &[
+            CLOSED_SERVER,
+            "--format",
+            "{{\u{a0}title\u{3000}}}",
+            "status",
+        ],
+        UNREACHABLE,
+    );
+    assert_exit(
+        &[
+            CLOSED_SERVER,
+            "--format",
+            "{{ default(title, '}}') }}",
+            "status",
+        ],
+        UNREACHABLE,
+    );
+}
+
+#[test]
+fn json_usage_errors_are_reported_as_json() {
+    let output = run(&[CLOSED_SERVER, "--json", "--timeout", "1e30", "status"]);
+    assert_eq!(output.status.code(), Some(USAGE));
+    let error: serde_json::Value = serde_json::from_slice(&output.stderr).unwrap();
+    assert_eq!(error["error"]["kind"], "invalid_usage");
+}
+
+#[test]
+fn scripts_cannot_run_scripts() {
+    let mut child = cli(&[CLOSED_SERVER, "run", "-"])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"status\nrun other.txt\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert_eq!(output.status.code(), Some(USAGE));
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("-:2: run:"));
+}
+
+#[test]
+fn unreachable_servers_are_reported() {
+    assert_exit(&[CLOSED_SERVER, "status"], UNREACHABLE);
+    assert_exit(&[CLOSED_SERVER, "wait", "track-change"], UNREACHABLE);
+}
+
+#[test]
+fn closed_stdout_is_not_a_crash() {
+    let config = temp_file("profiles.toml");
+    std::fs::write(&config, "[profiles.office]\nhost = \"office.lan\"\n").unwrap();
+
+    for _ in 0..5 {
+        let mut child = cli(&["profile", "list"])
+            .env("OSMEDIAMOTE_CONFIG", &config)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        drop(child.stdout.take());
+        assert_eq!(child.wait().unwrap().code(), Some(0));
+    }
+
+    let _ = std::fs::remove_file(config);
+}
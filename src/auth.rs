@@ -0,0 +1,77 @@
+use actix_web::{http::StatusCode, HttpRequest, HttpResponse, ResponseError};
+
+/// Environment variable holding the token required by elevated endpoints such as `/quit`.
+pub const ADMIN_TOKEN_ENV: &str = "OSMEDIAMOTE_ADMIN_TOKEN";
+
+#[derive(Debug)]
+pub enum AuthError {
+    MissingToken,
+    InvalidToken,
+    NotConfigured,
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::MissingToken => write!(f, "Missing bearer token"),
+            AuthError::InvalidToken => write!(f, "Invalid token"),
+            AuthError::NotConfigured => {
+                write!(f, "Endpoint disabled, set {ADMIN_TOKEN_ENV} to enable it")
+            }
+        }
+    }
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::MissingToken | AuthError::InvalidToken => StatusCode::UNAUTHORIZED,
+            AuthError::NotConfigured => StatusCode::FORBIDDEN,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type("text/plain; charset=utf-8")
+            .body(self.to_string())
+    }
+}
+
+/// Token for the elevated scope. Endpoints that require it stay disabled until one is set.
+#[derive(Debug, Clone)]
+pub struct AdminToken(Option<String>);
+
+impl AdminToken {
+    pub fn from_env() -> AdminToken {
+        AdminToken(
+            std::env::var(ADMIN_TOKEN_ENV)
+                .ok()
+                .filter(|t| !t.is_empty()),
+        )
+    }
+
+    /// Checks the request's `Authorization: Bearer` header against the admin token.
+    pub fn authorize(&self, req: &HttpRequest) -> Result<(), AuthError> {
+        let expected = self.0.as_ref().ok_or(AuthError::NotConfigured)?;
+        let provided = bearer_token(req).ok_or(AuthError::MissingToken)?;
+
+        if constant_time_eq(provided.as_bytes(), expected.as_bytes()) {
+            Ok(())
+        } else {
+            Err(AuthError::InvalidToken)
+        }
+    }
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(actix_web::http::header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
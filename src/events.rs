@@ -0,0 +1,49 @@
+use crate::media_backend::MediaBackend;
+use crate::playback_monitor::PlaybackMonitor;
+use actix_web::web::Bytes;
+use futures_util::Stream;
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+
+/// Comment lines are sent this often so proxies and clients can tell the stream is alive.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Server-sent events for `GET /events`.
+///
+/// Sends the `/status` snapshot as a `status` event when the stream opens and again whenever
+/// the [`PlaybackMonitor`] sees playback change, with `null` data while no player is running.
+pub fn status_stream(
+    backend: MediaBackend,
+    monitor: &PlaybackMonitor,
+) -> impl Stream<Item = Result<Bytes, Infallible>> {
+    let events = monitor.subscribe();
+
+    futures_util::stream::unfold(
+        (backend, events, true),
+        |(backend, mut events, first)| async move {
+            if !first {
+                match actix_web::rt::time::timeout(KEEP_ALIVE, events.recv()).await {
+                    Err(_) => {
+                        return Some((
+                            Ok(Bytes::from_static(b": keep-alive\n\n")),
+                            (backend, events, false),
+                        ));
+                    }
+                    Ok(Err(RecvError::Closed)) => return None,
+                    Ok(Ok(_) | Err(RecvError::Lagged(_))) => {}
+                }
+                // A track change comes as several events, one snapshot covers them all.
+                while let Ok(_) | Err(TryRecvError::Lagged(_)) = events.try_recv() {}
+            }
+
+            let data = match backend.call(|mc| mc.media_get_status()).await {
+                Ok(status) => serde_json::to_string(&status).unwrap_or_else(|_| "null".to_string()),
+                Err(_) => "null".to_string(),
+            };
+            let event = Bytes::from(format!("event: status\ndata: {data}\n\n"));
+
+            Some((Ok(event), (backend, events, false)))
+        },
+    )
+}
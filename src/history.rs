@@ -0,0 +1,296 @@
+use crate::playback_monitor::{PlaybackEvent, PlaybackMonitor, PlayedTrack};
+use crate::storage;
+use actix_web::{http::StatusCode, web, HttpResponse, ResponseError};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime};
+use serde::{Deserialize, Serialize};
+use std::ops::ControlFlow;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::error::RecvError;
+
+const HISTORY_FILE: &str = "history.jsonl";
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 500;
+/// Last moment of a day, so a date-only `to` includes the whole day.
+const END_OF_DAY: NaiveTime = NaiveTime::from_hms_nano_opt(23, 59, 59, 999_999_999).unwrap();
+
+#[derive(Debug)]
+pub enum HistoryError {
+    Invalid(String),
+    Storage(std::io::Error),
+}
+
+impl std::fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HistoryError::Invalid(e) => write!(f, "{e}"),
+            HistoryError::Storage(e) => write!(f, "Could not read the history: {e}"),
+        }
+    }
+}
+
+impl ResponseError for HistoryError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            HistoryError::Invalid(_) => StatusCode::BAD_REQUEST,
+            HistoryError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type("text/plain; charset=utf-8")
+            .body(self.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    #[serde(flatten)]
+    pub track: PlayedTrack,
+    pub ended_at: DateTime<Local>,
+}
+
+/// Query of `GET /history`, `from` and `to` are RFC 3339 times, local times or dates.
+#[derive(Debug, Default, Deserialize)]
+pub struct HistoryQuery {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub offset: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HistoryPage {
+    pub offset: usize,
+    /// Whether older entries match the filters, on the next pages.
+    pub more: bool,
+    /// Newest first.
+    pub entries: Vec<HistoryEntry>,
+}
+
+/// Log of every track the [`PlaybackMonitor`] saw, appended to `history.jsonl` in the data dir.
+#[derive(Clone)]
+pub struct History {
+    /// Serializes appends with reads so a page never sees a half-written line.
+    file_lock: Arc<Mutex<()>>,
+}
+
+impl History {
+    /// Starts recording the monitor's tracks, must be called on the actix runtime.
+    pub fn start(monitor: &PlaybackMonitor) -> History {
+        let history = History {
+            file_lock: Arc::new(Mutex::new(())),
+        };
+
+        actix_web::rt::spawn(history.clone().run(monitor.subscribe()));
+
+        history
+    }
+
+    /// Entries that were playing at any point between `from` and `to`.
+    pub async fn query(&self, query: HistoryQuery) -> Result<HistoryPage, HistoryError> {
+        let from = query
+            .from
+            .as_deref()
+            .map(|from| parse_time(from, NaiveTime::MIN))
+            .transpose()?;
+        let to = query
+            .to
+            .as_deref()
+            .map(|to| parse_time(to, END_OF_DAY))
+            .transpose()?;
+        let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+        if limit == 0 || limit > MAX_LIMIT {
+            return Err(HistoryError::Invalid(format!(
+                "Limit must be between 1 and {MAX_LIMIT}"
+            )));
+        }
+        let offset = query.offset;
+
+        let file_lock = self.file_lock.clone();
+        web::block(move || {
+            let _lock = file_lock.lock().unwrap();
+            read_page(from, to, offset, limit)
+        })
+        .await
+        .map_err(|e| HistoryError::Storage(std::io::Error::other(e)))?
+    }
+
+    async fn run(self, mut events: tokio::sync::broadcast::Receiver<PlaybackEvent>) {
+        loop {
+            match events.recv().await {
+                Ok(PlaybackEvent::TrackEnded { track, ended_at }) => {
+                    let entry = HistoryEntry { track, ended_at };
+                    let file_lock = self.file_lock.clone();
+                    let appended = web::block(move || {
+                        let _lock = file_lock.lock().unwrap();
+                        storage::append_json_line(HISTORY_FILE, &entry)
+                            .map_err(|e| format!("{}: {e}", entry.track.title))
+                    })
+                    .await;
+                    match appended {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => log::warn!("Could not record {e} in the history"),
+                        Err(e) => log::warn!("Could not record a track in the history: {e}"),
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => {
+                    log::warn!("History missed {missed} playback events");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+/// The `limit` entries playing between `from` and `to` after the newest `offset` of them,
+/// reading the file only back to the one after the page.
+fn read_page(
+    from: Option<DateTime<Local>>,
+    to: Option<DateTime<Local>>,
+    offset: usize,
+    limit: usize,
+) -> Result<HistoryPage, HistoryError> {
+    let mut skipped = 0;
+    let mut entries = Vec::new();
+    let mut more = false;
+    storage::read_json_lines_rev(HISTORY_FILE, |entry: HistoryEntry| {
+        // Entries are appended as tracks end, so older ones ended before `from` too.
+        if from.is_some_and(|from| entry.ended_at < from) {
+            return ControlFlow::Break(());
+        }
+        if to.is_some_and(|to| entry.track.started_at > to) {
+            return ControlFlow::Continue(());
+        }
+        if skipped < offset {
+            skipped += 1;
+        } else if entries.len() < limit {
+            entries.push(entry);
+        } else {
+            more = true;
+            return ControlFlow::Break(());
+        }
+        ControlFlow::Continue(())
+    })
+    .map_err(HistoryError::Storage)?;
+    Ok(HistoryPage {
+        offset,
+        more,
+        entries,
+    })
+}
+
+/// Parses an RFC 3339 or local time, or a local date taken at `date_time`.
+fn parse_time(value: &str, date_time: NaiveTime) -> Result<DateTime<Local>, HistoryError> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Local));
+    }
+    let naive = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M"))
+        .or_else(|_| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d").map(|date| date.and_time(date_time))
+        })
+        .map_err(|_| HistoryError::Invalid(format!("Invalid time: {value}")))?;
+    naive
+        .and_local_timezone(Local)
+        .earliest()
+        .ok_or_else(|| HistoryError::Invalid(format!("Invalid local time: {value}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn entry(title: &str, started_at: DateTime<Local>) -> HistoryEntry {
+        HistoryEntry {
+            track: PlayedTrack {
+                player: "test".to_string(),
+                title: title.to_string(),
+                artist: "Artist".to_string(),
+                album: "Album".to_string(),
+                duration: 180.0,
+                started_at,
+                played: 180.0,
+            },
+            ended_at: started_at + chrono::Duration::minutes(3),
+        }
+    }
+
+    fn history() -> History {
+        storage::use_temp_data_dir();
+        History {
+            file_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    fn query(from: Option<&str>, to: Option<&str>, offset: usize) -> HistoryQuery {
+        HistoryQuery {
+            from: from.map(str::to_string),
+            to: to.map(str::to_string),
+            limit: Some(2),
+            offset,
+        }
+    }
+
+    fn titles(page: &HistoryPage) -> Vec<&str> {
+        page.entries
+            .iter()
+            .map(|entry| entry.track.title.as_str())
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn pages_date_filtered_entries() {
+        let history = history();
+        let day = |d, h| Local.with_ymd_and_hms(2025, 1, d, h, 0, 0).unwrap();
+        for (title, started_at) in [
+            ("before", day(5, 12)),
+            ("morning", day(6, 8)),
+            ("noon", day(6, 12)),
+            ("evening", day(6, 23)),
+            ("after", day(7, 12)),
+        ] {
+            storage::append_json_line(HISTORY_FILE, &entry(title, started_at)).unwrap();
+        }
+
+        let range = |offset| query(Some("2025-01-06"), Some("2025-01-06"), offset);
+        let first = history.query(range(0)).await.unwrap();
+        assert_eq!(titles(&first), ["evening", "noon"]);
+        assert!(first.more);
+        let second = history.query(range(2)).await.unwrap();
+        assert_eq!(titles(&second), ["morning"]);
+        assert!(!second.more);
+    }
+
+    #[actix_web::test]
+    async fn huge_offset_gives_an_empty_page() {
+        let page = history()
+            .query(query(None, None, usize::MAX))
+            .await
+            .unwrap();
+
+        assert!(page.entries.is_empty());
+        assert!(!page.more);
+        assert_eq!(page.offset, usize::MAX);
+    }
+
+    #[test]
+    fn date_only_to_covers_the_whole_day() {
+        let end = parse_time("2025-01-06", END_OF_DAY).unwrap();
+
+        assert_eq!(
+            end.naive_local().to_string(),
+            "2025-01-06 23:59:59.999999999"
+        );
+        assert_eq!(
+            parse_time("2025-01-06", NaiveTime::MIN)
+                .unwrap()
+                .naive_local()
+                .to_string(),
+            "2025-01-06 00:00:00"
+        );
+    }
+}
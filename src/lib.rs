@@ -1,3 +1,17 @@
+pub mod auth;
+pub mod events;
+pub mod history;
+pub mod media_backend;
 #[cfg_attr(not(target_os = "windows"), path = "media_controller.rs")]
 #[cfg_attr(target_os = "windows", path = "media_controller_win.rs")]
 pub mod media_controller;
+pub mod media_status;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod open_uri;
+pub mod playback_monitor;
+pub mod scheduler;
+pub mod scrobbler;
+pub mod sleep_timer;
+pub mod storage;
+pub mod webhooks;
@@ -1,121 +1,609 @@
-use actix_web::{get, middleware::Logger, put, web, App, HttpResponse, HttpServer, Responder};
-use os_mediamote::media_controller;
+use actix_web::dev::Service;
+use actix_web::{
+    delete, get, middleware::Logger, post, put, web, App, HttpRequest, HttpResponse, HttpServer,
+    Responder,
+};
+use os_mediamote::auth::AdminToken;
+use os_mediamote::events::status_stream;
+use os_mediamote::history::{History, HistoryError, HistoryQuery};
+use os_mediamote::media_backend::{with_player, MediaBackend, MediaBackendError};
+use os_mediamote::open_uri::SchemeAllowlist;
+use os_mediamote::playback_monitor::PlaybackMonitor;
+use os_mediamote::scheduler::{ScheduleRequest, Scheduler};
+use os_mediamote::scrobbler::{Scrobbler, ScrobblerConfig};
+use os_mediamote::sleep_timer::{SleepTimer, SleepTimerError, TimerRequest};
+use os_mediamote::webhooks::{WebhookRequest, Webhooks};
+use serde::Deserialize;
 
 struct AppState {
-    mc: media_controller::MediaController,
+    backend: MediaBackend,
+    admin_token: AdminToken,
+    open_schemes: SchemeAllowlist,
+    sleep_timer: SleepTimer,
+    scheduler: Scheduler,
+    history: History,
+    scrobbler: Scrobbler,
+    webhooks: Webhooks,
+    monitor: PlaybackMonitor,
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
 
-    HttpServer::new(|| {
+    let data = web::Data::new(app_state()?);
+
+    HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(AppState {
-                mc: media_controller::MediaController::new().unwrap(),
-            }))
+            .app_data(data.clone())
+            .wrap_fn(|req, srv| {
+                let player = url::form_urlencoded::parse(req.query_string().as_bytes())
+                    .find(|(key, _)| key == "player")
+                    .map(|(_, player)| player.into_owned());
+                with_player(player, srv.call(req))
+            })
             .wrap(Logger::default())
             .service(pause)
             .service(play)
             .service(play_pause)
             .service(play_next)
             .service(play_prev)
+            .service(stop)
+            .service(raise)
+            .service(quit)
             .service(title)
             .service(art)
             .service(artist)
             .service(duration)
             .service(position_get)
             .service(position_put)
+            .service(seek)
+            .service(volume_get)
+            .service(volume_put)
             .service(is_playing)
+            .service(status)
+            .service(events)
+            .service(capabilities)
+            .service(players)
+            .service(track_list)
+            .service(track_list_go_to)
+            .service(track_list_add)
+            .service(track_list_remove)
+            .service(playlists)
+            .service(playlists_activate)
+            .service(open)
+            .service(timer_get)
+            .service(timer_post)
+            .service(timer_delete)
+            .service(schedules_log)
+            .service(schedules_get)
+            .service(schedules_post)
+            .service(schedule_get)
+            .service(schedules_put)
+            .service(schedules_delete)
+            .service(history)
+            .service(scrobbler_status)
+            .service(webhooks_failures)
+            .service(webhooks_get)
+            .service(webhooks_post)
+            .service(webhook_get)
+            .service(webhooks_put)
+            .service(webhooks_delete)
             .service(ping)
     })
     .bind(("0.0.0.0", 65420))?
+    // `/events` streams never finish on their own, don't let them hold up a restart.
+    .shutdown_timeout(2)
     .run()
     .await
 }
 
+/// Starts the backend and the stores behind the handlers, logging which one failed.
+fn app_state() -> std::io::Result<AppState> {
+    let backend = MediaBackend::spawn().inspect_err(|e| {
+        log::error!("Failed to start the media backend: {e}");
+    })?;
+    let scheduler = Scheduler::start(backend.clone()).inspect_err(|e| {
+        log::error!("Failed to load the schedules: {e}");
+    })?;
+    let monitor = PlaybackMonitor::start(backend.clone());
+    let scrobbler = Scrobbler::start(ScrobblerConfig::from_env(), &monitor).inspect_err(|e| {
+        log::error!("Failed to start the scrobbler: {e}");
+    })?;
+    let webhooks = Webhooks::start(&monitor).inspect_err(|e| {
+        log::error!("Failed to load the webhooks: {e}");
+    })?;
+    #[cfg(feature = "mqtt")]
+    match os_mediamote::mqtt::MqttConfig::from_env() {
+        Ok(Some(config)) => {
+            os_mediamote::mqtt::MqttBridge::start(config, backend.clone(), &monitor)
+        }
+        Ok(None) => {}
+        Err(e) => log::error!("MQTT bridge disabled: {e}"),
+    }
+    Ok(AppState {
+        history: History::start(&monitor),
+        scrobbler,
+        webhooks,
+        monitor,
+        sleep_timer: SleepTimer::new(backend.clone()),
+        scheduler,
+        backend,
+        admin_token: AdminToken::from_env(),
+        open_schemes: SchemeAllowlist::from_env(),
+    })
+}
+
 #[get("/pause")]
-async fn pause(data: web::Data<AppState>) -> impl Responder {
-    data.mc.media_pause().unwrap();
-    HttpResponse::Ok()
+async fn pause(data: web::Data<AppState>) -> Result<impl Responder, MediaBackendError> {
+    data.backend.call(|mc| mc.media_pause()).await?;
+    Ok(HttpResponse::Ok())
 }
 
 #[get("/play")]
-async fn play(data: web::Data<AppState>) -> impl Responder {
-    data.mc.media_play().unwrap();
-    HttpResponse::Ok()
+async fn play(data: web::Data<AppState>) -> Result<impl Responder, MediaBackendError> {
+    data.backend.call(|mc| mc.media_play()).await?;
+    Ok(HttpResponse::Ok())
 }
 
 #[get("/play_pause")]
-async fn play_pause(data: web::Data<AppState>) -> impl Responder {
-    data.mc.media_play_pause().unwrap();
-    HttpResponse::Ok()
+async fn play_pause(data: web::Data<AppState>) -> Result<impl Responder, MediaBackendError> {
+    data.backend.call(|mc| mc.media_play_pause()).await?;
+    Ok(HttpResponse::Ok())
 }
 
 #[get("/play_next")]
-async fn play_next(data: web::Data<AppState>) -> impl Responder {
-    data.mc.media_play_next().unwrap();
-    HttpResponse::Ok()
+async fn play_next(data: web::Data<AppState>) -> Result<impl Responder, MediaBackendError> {
+    data.backend.call(|mc| mc.media_play_next()).await?;
+    Ok(HttpResponse::Ok())
 }
 
 #[get("/play_prev")]
-async fn play_prev(data: web::Data<AppState>) -> impl Responder {
-    data.mc.media_play_prev().unwrap();
-    HttpResponse::Ok()
+async fn play_prev(data: web::Data<AppState>) -> Result<impl Responder, MediaBackendError> {
+    data.backend.call(|mc| mc.media_play_prev()).await?;
+    Ok(HttpResponse::Ok())
+}
+
+#[get("/stop")]
+async fn stop(data: web::Data<AppState>) -> Result<impl Responder, MediaBackendError> {
+    data.backend.call(|mc| mc.media_stop()).await?;
+    Ok(HttpResponse::Ok())
+}
+
+#[get("/raise")]
+async fn raise(data: web::Data<AppState>) -> Result<impl Responder, MediaBackendError> {
+    data.backend.call(|mc| mc.media_raise()).await?;
+    Ok(HttpResponse::Ok())
+}
+
+#[post("/quit")]
+async fn quit(req: HttpRequest, data: web::Data<AppState>) -> actix_web::Result<impl Responder> {
+    data.admin_token.authorize(&req)?;
+    data.backend.call(|mc| mc.media_quit()).await?;
+    Ok(HttpResponse::Ok())
 }
 
 #[get("/title")]
-async fn title(data: web::Data<AppState>) -> impl Responder {
-    let title = data.mc.media_get_title().unwrap();
-    HttpResponse::Ok()
+async fn title(data: web::Data<AppState>) -> Result<impl Responder, MediaBackendError> {
+    let title = data.backend.call(|mc| mc.media_get_title()).await?;
+    Ok(HttpResponse::Ok()
         .content_type("text/plain; charset=utf-8")
-        .body(title)
+        .body(title))
 }
 
 #[get("/artist")]
-async fn artist(data: web::Data<AppState>) -> impl Responder {
-    let artist = data.mc.media_get_artist().unwrap();
-    HttpResponse::Ok().body(artist)
+async fn artist(data: web::Data<AppState>) -> Result<impl Responder, MediaBackendError> {
+    let artist = data.backend.call(|mc| mc.media_get_artist()).await?;
+    Ok(HttpResponse::Ok().body(artist))
 }
 
 #[get("/art")]
-async fn art(data: web::Data<AppState>) -> impl Responder {
-    let art = data.mc.media_get_art().unwrap();
-    HttpResponse::Ok().body(art)
+async fn art(data: web::Data<AppState>) -> Result<impl Responder, MediaBackendError> {
+    let art = data.backend.call(|mc| mc.media_get_art()).await?;
+    Ok(HttpResponse::Ok().body(art))
 }
 
 #[get("/duration")]
-async fn duration(data: web::Data<AppState>) -> impl Responder {
-    let duration = data.mc.media_get_duration().unwrap();
-    HttpResponse::Ok()
+async fn duration(data: web::Data<AppState>) -> Result<impl Responder, MediaBackendError> {
+    let duration = data.backend.call(|mc| mc.media_get_duration()).await?;
+    Ok(HttpResponse::Ok()
         .content_type("text/plain; charset=utf-8")
-        .body(format!("{duration}"))
+        .body(format!("{duration}")))
 }
 
 #[get("/position")]
-async fn position_get(data: web::Data<AppState>) -> impl Responder {
-    let position = data.mc.media_get_position().unwrap();
-    HttpResponse::Ok()
+async fn position_get(data: web::Data<AppState>) -> Result<impl Responder, MediaBackendError> {
+    let position = data.backend.call(|mc| mc.media_get_position()).await?;
+    Ok(HttpResponse::Ok()
         .content_type("text/plain; charset=utf-8")
-        .body(format!("{position}"))
+        .body(format!("{position}")))
 }
 
 #[put("/position/{pos_sec}")]
-async fn position_put(path: web::Path<u64>, data: web::Data<AppState>) -> impl Responder {
+async fn position_put(
+    path: web::Path<u64>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, MediaBackendError> {
     let pos_sec = path.into_inner();
-    data.mc.media_set_position(pos_sec).unwrap();
-    HttpResponse::Ok()
+    data.backend
+        .call(move |mc| mc.media_set_position(pos_sec))
+        .await?;
+    Ok(HttpResponse::Ok())
+}
+
+#[put("/seek/{offset_sec}")]
+async fn seek(
+    path: web::Path<i64>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, MediaBackendError> {
+    let offset_sec = path.into_inner();
+    data.backend
+        .call(move |mc| mc.media_seek(offset_sec))
+        .await?;
+    Ok(HttpResponse::Ok())
+}
+
+#[get("/volume")]
+async fn volume_get(data: web::Data<AppState>) -> Result<impl Responder, MediaBackendError> {
+    let volume = data.backend.call(|mc| mc.media_get_volume()).await?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .body(format!("{volume}")))
+}
+
+#[put("/volume/{volume}")]
+async fn volume_put(
+    path: web::Path<f64>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, MediaBackendError> {
+    let volume = path.into_inner();
+    data.backend
+        .call(move |mc| mc.media_set_volume(volume))
+        .await?;
+    Ok(HttpResponse::Ok())
 }
 
 #[get("/is_playing")]
-async fn is_playing(data: web::Data<AppState>) -> impl Responder {
-    let is_playing = data.mc.media_is_playing().unwrap();
-    HttpResponse::Ok()
+async fn is_playing(data: web::Data<AppState>) -> Result<impl Responder, MediaBackendError> {
+    let is_playing = data.backend.call(|mc| mc.media_is_playing()).await?;
+    Ok(HttpResponse::Ok()
         .content_type("text/plain; charset=utf-8")
-        .body(format!("{is_playing}"))
+        .body(format!("{is_playing}")))
+}
+
+#[get("/status")]
+async fn status(data: web::Data<AppState>) -> Result<impl Responder, MediaBackendError> {
+    let status = data.backend.call(|mc| mc.media_get_status()).await?;
+    Ok(HttpResponse::Ok().json(status))
+}
+
+#[get("/events")]
+async fn events(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(status_stream(data.backend.clone(), &data.monitor))
+}
+
+#[get("/players")]
+async fn players(data: web::Data<AppState>) -> Result<impl Responder, MediaBackendError> {
+    let players = data.backend.call(|mc| mc.media_get_players()).await?;
+    Ok(HttpResponse::Ok().json(players))
+}
+
+#[get("/capabilities")]
+async fn capabilities(data: web::Data<AppState>) -> Result<impl Responder, MediaBackendError> {
+    let capabilities = data.backend.call(|mc| mc.media_get_capabilities()).await?;
+    Ok(HttpResponse::Ok().json(capabilities))
+}
+
+#[derive(Deserialize)]
+struct TrackIdRequest {
+    track_id: String,
+}
+
+#[derive(Deserialize)]
+struct AddTrackRequest {
+    uri: String,
+    after: Option<String>,
+    #[serde(default)]
+    set_as_current: bool,
+}
+
+#[get("/tracklist")]
+async fn track_list(data: web::Data<AppState>) -> Result<impl Responder, MediaBackendError> {
+    let track_list = data.backend.call(|mc| mc.media_get_track_list()).await?;
+    Ok(HttpResponse::Ok().json(track_list))
+}
+
+#[post("/tracklist/goto")]
+async fn track_list_go_to(
+    body: web::Json<TrackIdRequest>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, MediaBackendError> {
+    let TrackIdRequest { track_id } = body.into_inner();
+    data.backend
+        .call(move |mc| mc.media_go_to(&track_id))
+        .await?;
+    Ok(HttpResponse::Ok())
+}
+
+#[post("/tracklist/add")]
+async fn track_list_add(
+    body: web::Json<AddTrackRequest>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<impl Responder> {
+    let AddTrackRequest {
+        uri,
+        after,
+        set_as_current,
+    } = body.into_inner();
+    let uri = data.open_schemes.to_uri(&uri)?;
+    data.backend
+        .call(move |mc| mc.media_add_track(uri.as_str(), after.as_deref(), set_as_current))
+        .await?;
+    Ok(HttpResponse::Ok())
+}
+
+#[post("/tracklist/remove")]
+async fn track_list_remove(
+    body: web::Json<TrackIdRequest>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, MediaBackendError> {
+    let TrackIdRequest { track_id } = body.into_inner();
+    data.backend
+        .call(move |mc| mc.media_remove_track(&track_id))
+        .await?;
+    Ok(HttpResponse::Ok())
+}
+
+#[derive(Deserialize)]
+struct PlaylistsQuery {
+    order: Option<String>,
+    #[serde(default)]
+    reverse: bool,
+}
+
+#[derive(Deserialize)]
+struct ActivatePlaylistRequest {
+    playlist_id: String,
+}
+
+#[get("/playlists")]
+async fn playlists(
+    query: web::Query<PlaylistsQuery>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, MediaBackendError> {
+    let PlaylistsQuery { order, reverse } = query.into_inner();
+    let playlists = data
+        .backend
+        .call(move |mc| mc.media_get_playlists(order.as_deref(), reverse))
+        .await?;
+    Ok(HttpResponse::Ok().json(playlists))
+}
+
+#[post("/playlists/activate")]
+async fn playlists_activate(
+    body: web::Json<ActivatePlaylistRequest>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, MediaBackendError> {
+    let ActivatePlaylistRequest { playlist_id } = body.into_inner();
+    data.backend
+        .call(move |mc| mc.media_activate_playlist(&playlist_id))
+        .await?;
+    Ok(HttpResponse::Ok())
+}
+
+#[derive(Deserialize)]
+struct OpenRequest {
+    uri: String,
+}
+
+#[post("/open")]
+async fn open(
+    body: web::Json<OpenRequest>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<impl Responder> {
+    let uri = data.open_schemes.to_uri(&body.uri)?;
+    data.backend.call(move |mc| mc.media_open_uri(&uri)).await?;
+    Ok(HttpResponse::Ok())
+}
+
+#[get("/timer")]
+async fn timer_get(data: web::Data<AppState>) -> Result<impl Responder, SleepTimerError> {
+    let timer = data.sleep_timer.status()?;
+    Ok(HttpResponse::Ok().json(timer))
+}
+
+#[post("/timer")]
+async fn timer_post(
+    body: web::Json<TimerRequest>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, SleepTimerError> {
+    let timer = data.sleep_timer.start(body.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(timer))
+}
+
+#[delete("/timer")]
+async fn timer_delete(data: web::Data<AppState>) -> Result<impl Responder, SleepTimerError> {
+    data.sleep_timer.cancel()?;
+    Ok(HttpResponse::Ok())
+}
+
+#[get("/schedules")]
+async fn schedules_get(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> actix_web::Result<impl Responder> {
+    data.admin_token.authorize(&req)?;
+    Ok(HttpResponse::Ok().json(data.scheduler.list()))
+}
+
+#[post("/schedules")]
+async fn schedules_post(
+    req: HttpRequest,
+    body: web::Json<ScheduleRequest>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<impl Responder> {
+    data.admin_token.authorize(&req)?;
+    let schedule = data.scheduler.add(body.into_inner())?;
+    Ok(HttpResponse::Ok().json(schedule))
+}
+
+#[get("/schedules/{id}")]
+async fn schedule_get(
+    req: HttpRequest,
+    id: web::Path<u64>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<impl Responder> {
+    data.admin_token.authorize(&req)?;
+    let schedule = data.scheduler.get(*id)?;
+    Ok(HttpResponse::Ok().json(schedule))
+}
+
+#[put("/schedules/{id}")]
+async fn schedules_put(
+    req: HttpRequest,
+    id: web::Path<u64>,
+    body: web::Json<ScheduleRequest>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<impl Responder> {
+    data.admin_token.authorize(&req)?;
+    let schedule = data.scheduler.update(*id, body.into_inner())?;
+    Ok(HttpResponse::Ok().json(schedule))
+}
+
+#[delete("/schedules/{id}")]
+async fn schedules_delete(
+    req: HttpRequest,
+    id: web::Path<u64>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<impl Responder> {
+    data.admin_token.authorize(&req)?;
+    data.scheduler.remove(*id)?;
+    Ok(HttpResponse::Ok())
+}
+
+#[get("/schedules/log")]
+async fn schedules_log(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> actix_web::Result<impl Responder> {
+    data.admin_token.authorize(&req)?;
+    Ok(HttpResponse::Ok().json(data.scheduler.log()))
+}
+
+#[get("/history")]
+async fn history(
+    query: web::Query<HistoryQuery>,
+    data: web::Data<AppState>,
+) -> Result<impl Responder, HistoryError> {
+    let page = data.history.query(query.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(page))
+}
+
+#[get("/scrobbler")]
+async fn scrobbler_status(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(data.scrobbler.status())
+}
+
+#[get("/webhooks")]
+async fn webhooks_get(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> actix_web::Result<impl Responder> {
+    data.admin_token.authorize(&req)?;
+    Ok(HttpResponse::Ok().json(data.webhooks.list()))
+}
+
+#[post("/webhooks")]
+async fn webhooks_post(
+    req: HttpRequest,
+    body: web::Json<WebhookRequest>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<impl Responder> {
+    data.admin_token.authorize(&req)?;
+    let webhook = data.webhooks.add(body.into_inner())?;
+    Ok(HttpResponse::Ok().json(webhook))
+}
+
+#[get("/webhooks/{id}")]
+async fn webhook_get(
+    req: HttpRequest,
+    id: web::Path<u64>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<impl Responder> {
+    data.admin_token.authorize(&req)?;
+    let webhook = data.webhooks.get(*id)?;
+    Ok(HttpResponse::Ok().json(webhook))
+}
+
+#[put("/webhooks/{id}")]
+async fn webhooks_put(
+    req: HttpRequest,
+    id: web::Path<u64>,
+    body: web::Json<WebhookRequest>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<impl Responder> {
+    data.admin_token.authorize(&req)?;
+    let webhook = data.webhooks.update(*id, body.into_inner())?;
+    Ok(HttpResponse::Ok().json(webhook))
+}
+
+#[delete("/webhooks/{id}")]
+async fn webhooks_delete(
+    req: HttpRequest,
+    id: web::Path<u64>,
+    data: web::Data<AppState>,
+) -> actix_web::Result<impl Responder> {
+    data.admin_token.authorize(&req)?;
+    data.webhooks.remove(*id)?;
+    Ok(HttpResponse::Ok())
+}
+
+#[get("/webhooks/failures")]
+async fn webhooks_failures(
+    req: HttpRequest,
+    data: web::Data<AppState>,
+) -> actix_web::Result<impl Responder> {
+    data.admin_token.authorize(&req)?;
+    Ok(HttpResponse::Ok().json(data.webhooks.failures()))
 }
 
 #[get("/ping")]
 async fn ping() -> impl Responder {
     HttpResponse::Ok()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test};
+
+    #[actix_web::test]
+    async fn track_list_add_rejects_disallowed_schemes() {
+        let dir = std::env::temp_dir().join(format!("osmediamote-main-{}", std::process::id()));
+        std::env::set_var(os_mediamote::storage::DATA_DIR_ENV, &dir);
+        std::env::remove_var(os_mediamote::open_uri::OPEN_SCHEMES_ENV);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(app_state().unwrap()))
+                .service(track_list_add),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/tracklist/add")
+            .set_json(serde_json::json!({"uri": "file:///etc/passwd"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body = test::read_body(resp).await;
+        assert_eq!(
+            body,
+            "Scheme file is not allowed, see OSMEDIAMOTE_OPEN_SCHEMES"
+        );
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,167 @@
+use crate::media_controller::{MediaController, MediaControllerError};
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use std::panic::AssertUnwindSafe;
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+/// Longest time a request waits for the backend before giving up on a call.
+pub const CALL_TIMEOUT: Duration = Duration::from_secs(2);
+
+tokio::task_local! {
+    /// Player named by the request being served, see [`with_player`].
+    static PLAYER: Option<String>;
+}
+
+type Job = Box<dyn FnOnce(Result<&MediaController, &MediaControllerError>) + Send>;
+
+#[derive(Debug)]
+pub enum MediaBackendError {
+    Controller(MediaControllerError),
+    Unavailable(String),
+    Timeout,
+    Disconnected,
+    /// The call panicked, the controller is recreated before the next one.
+    Panicked,
+}
+
+impl std::fmt::Display for MediaBackendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MediaBackendError::Controller(e) => write!(f, "{e}"),
+            MediaBackendError::Unavailable(e) => write!(f, "Media controller unavailable: {e}"),
+            MediaBackendError::Timeout => write!(f, "Media backend call timed out"),
+            MediaBackendError::Disconnected => write!(f, "Media backend is not running"),
+            MediaBackendError::Panicked => write!(f, "Media backend call failed unexpectedly"),
+        }
+    }
+}
+
+impl ResponseError for MediaBackendError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            MediaBackendError::Controller(e) if e.is_unsupported() => StatusCode::NOT_IMPLEMENTED,
+            MediaBackendError::Controller(e) if e.is_invalid_argument() => StatusCode::BAD_REQUEST,
+            MediaBackendError::Controller(e) if e.is_no_player() => StatusCode::NOT_FOUND,
+            MediaBackendError::Controller(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            MediaBackendError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            MediaBackendError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            MediaBackendError::Disconnected => StatusCode::SERVICE_UNAVAILABLE,
+            MediaBackendError::Panicked => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type("text/plain; charset=utf-8")
+            .body(self.to_string())
+    }
+}
+
+/// Handle to the thread that owns the `MediaController`.
+///
+/// D-Bus and WinRT calls block, so they run on a dedicated thread and handlers only await the
+/// reply, keeping actix workers free to serve other clients while a player is slow to answer.
+/// The controller is created lazily and recreated after its connection drops or a call
+/// panics, so the server can start before a session bus exists. Should the thread itself stop,
+/// the next call starts a new one.
+#[derive(Clone)]
+pub struct MediaBackend {
+    sender: Arc<Mutex<mpsc::Sender<Job>>>,
+    timeout: Duration,
+}
+
+impl MediaBackend {
+    pub fn spawn() -> std::io::Result<MediaBackend> {
+        Ok(MediaBackend {
+            sender: Arc::new(Mutex::new(spawn_thread()?)),
+            timeout: CALL_TIMEOUT,
+        })
+    }
+
+    /// Queues `job` on the backend thread, starting a new thread if the last one stopped.
+    fn send(&self, job: Job) -> Result<(), MediaBackendError> {
+        let mut sender = self.sender.lock().unwrap_or_else(PoisonError::into_inner);
+        let Err(mpsc::SendError(job)) = sender.send(job) else {
+            return Ok(());
+        };
+
+        log::warn!("Media backend thread stopped, starting a new one");
+        *sender = spawn_thread().map_err(|e| {
+            log::error!("Could not start the media backend thread: {e}");
+            MediaBackendError::Disconnected
+        })?;
+        sender
+            .send(job)
+            .map_err(|_| MediaBackendError::Disconnected)
+    }
+
+    /// Runs `f` on the backend thread and waits at most `CALL_TIMEOUT` for its result.
+    pub async fn call<T, F>(&self, f: F) -> Result<T, MediaBackendError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&MediaController) -> Result<T, MediaControllerError> + Send + 'static,
+    {
+        let player = PLAYER.try_with(Clone::clone).ok().flatten();
+        let (result_sender, result_receiver) = tokio::sync::oneshot::channel();
+        self.send(Box::new(move |mc| {
+            let result = match mc {
+                Ok(mc) => std::panic::catch_unwind(AssertUnwindSafe(|| {
+                    mc.select_player(player.as_deref());
+                    f(mc).map_err(MediaBackendError::Controller)
+                })),
+                Err(e) => Ok(Err(MediaBackendError::Unavailable(e.to_string()))),
+            };
+            match result {
+                Ok(result) => {
+                    let _ = result_sender.send(result);
+                }
+                // Answered first, the thread then recreates the controller.
+                Err(panic) => {
+                    let _ = result_sender.send(Err(MediaBackendError::Panicked));
+                    std::panic::resume_unwind(panic);
+                }
+            }
+        }))?;
+
+        match actix_web::rt::time::timeout(self.timeout, result_receiver).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(MediaBackendError::Disconnected),
+            Err(_) => Err(MediaBackendError::Timeout),
+        }
+    }
+}
+
+/// Runs `future` with the backend calls it makes controlling the player named `player`
+/// instead of the active one, for requests with a `player` query parameter.
+pub async fn with_player<F: std::future::Future>(player: Option<String>, future: F) -> F::Output {
+    PLAYER.scope(player, future).await
+}
+
+/// Starts the thread owning the controller, which runs jobs until every sender is gone.
+fn spawn_thread() -> std::io::Result<mpsc::Sender<Job>> {
+    let (sender, receiver) = mpsc::channel::<Job>();
+
+    std::thread::Builder::new()
+        .name("media-backend".to_string())
+        .spawn(move || {
+            let mut mc = connect();
+            for job in receiver {
+                if mc.as_ref().is_ok_and(|mc| !mc.is_connected()) {
+                    log::warn!("Media controller lost its connection, reconnecting");
+                    mc = connect();
+                } else if mc.is_err() {
+                    mc = connect();
+                }
+                if std::panic::catch_unwind(AssertUnwindSafe(|| job(mc.as_ref()))).is_err() {
+                    log::error!("Media backend call panicked, reconnecting");
+                    mc = connect();
+                }
+            }
+        })?;
+
+    Ok(sender)
+}
+
+fn connect() -> Result<MediaController, MediaControllerError> {
+    MediaController::new().inspect_err(|e| log::warn!("Media controller unavailable: {e}"))
+}
@@ -1,114 +1,405 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use dbus::blocking::stdintf::org_freedesktop_dbus::{Properties, PropertiesPropertiesChanged};
+use dbus::blocking::Connection;
+use dbus::message::{MatchRule, SignalArgs};
 use mpris::PlayerFinder;
 
+use crate::media_status::{
+    unix_millis, Capabilities, MediaStatus, Player, Playlist, Playlists, Track, TrackList,
+};
+
+const MPRIS2_PREFIX: &str = "org.mpris.MediaPlayer2.";
+const MPRIS2_PATH: &str = "/org/mpris/MediaPlayer2";
+const MPRIS2_INTERFACE: &str = "org.mpris.MediaPlayer2";
+const MPRIS2_PLAYER_INTERFACE: &str = "org.mpris.MediaPlayer2.Player";
+const MPRIS2_PLAYLISTS_INTERFACE: &str = "org.mpris.MediaPlayer2.Playlists";
+const PROPERTIES_TIMEOUT: Duration = Duration::from_millis(500);
+
 #[derive(Debug)]
 pub struct MediaControllerError {
     finding_error: Option<mpris::FindingError>,
     dbus_error: Option<mpris::DBusError>,
+    detail: Option<ErrorDetail>,
 }
 
+/// What went wrong, for errors that are neither from the player nor from D-Bus.
 #[derive(Debug)]
+enum ErrorDetail {
+    /// The player does not allow the operation.
+    Unsupported(&'static str),
+    /// The request carried a value the player cannot accept.
+    InvalidArgument(String),
+    Failed(String),
+}
+
+impl MediaControllerError {
+    /// The current player does not allow `operation`.
+    pub fn unsupported(operation: &'static str) -> Self {
+        MediaControllerError {
+            finding_error: None,
+            dbus_error: None,
+            detail: Some(ErrorDetail::Unsupported(operation)),
+        }
+    }
+
+    /// The request carried a value the player cannot accept.
+    pub fn invalid_argument(message: String) -> Self {
+        MediaControllerError {
+            finding_error: None,
+            dbus_error: None,
+            detail: Some(ErrorDetail::InvalidArgument(message)),
+        }
+    }
+
+    /// The call failed for a reason other than the player or D-Bus, e.g. reading a file.
+    pub fn failed(message: String) -> Self {
+        MediaControllerError {
+            finding_error: None,
+            dbus_error: None,
+            detail: Some(ErrorDetail::Failed(message)),
+        }
+    }
+
+    pub fn is_unsupported(&self) -> bool {
+        matches!(self.detail, Some(ErrorDetail::Unsupported(_)))
+    }
+
+    pub fn is_invalid_argument(&self) -> bool {
+        matches!(self.detail, Some(ErrorDetail::InvalidArgument(_)))
+    }
+
+    /// No player is running, or none matches the requested one.
+    pub fn is_no_player(&self) -> bool {
+        matches!(self.finding_error, Some(mpris::FindingError::NoPlayerFound))
+    }
+}
+
+impl std::fmt::Display for MediaControllerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(e) = &self.finding_error {
+            write!(f, "{e}")
+        } else if let Some(e) = &self.dbus_error {
+            write!(f, "{e}")
+        } else {
+            match &self.detail {
+                Some(ErrorDetail::Unsupported(operation)) => {
+                    write!(f, "The current player does not support {operation}")
+                }
+                Some(ErrorDetail::InvalidArgument(message) | ErrorDetail::Failed(message)) => {
+                    write!(f, "{message}")
+                }
+                None => write!(f, "Unknown media controller error"),
+            }
+        }
+    }
+}
+
+impl From<mpris::FindingError> for MediaControllerError {
+    fn from(e: mpris::FindingError) -> Self {
+        MediaControllerError {
+            finding_error: Some(e),
+            dbus_error: None,
+            detail: None,
+        }
+    }
+}
+
+impl From<mpris::DBusError> for MediaControllerError {
+    fn from(e: mpris::DBusError) -> Self {
+        MediaControllerError {
+            finding_error: None,
+            dbus_error: Some(e),
+            detail: None,
+        }
+    }
+}
+
+impl From<dbus::Error> for MediaControllerError {
+    fn from(e: dbus::Error) -> Self {
+        MediaControllerError::from(mpris::DBusError::from(e))
+    }
+}
+
+/// Last known playback position, extrapolated forward while the player is playing.
+#[derive(Debug, Clone, Copy)]
+struct PositionSample {
+    position: Duration,
+    rate: f64,
+    playing: bool,
+    length: Option<Duration>,
+    sampled_at: Instant,
+}
+
+impl PositionSample {
+    fn position_at(&self, now: Instant) -> Duration {
+        let mut position = self.position;
+        if self.playing && self.rate > 0.0 {
+            position += now
+                .saturating_duration_since(self.sampled_at)
+                .mul_f64(self.rate);
+        }
+        match self.length {
+            Some(length) if !length.is_zero() => position.min(length),
+            _ => position,
+        }
+    }
+}
+
+/// Listens on the session bus for signals that can change which player is the active one.
+///
+/// `NameOwnerChanged` for an MPRIS bus name means a player appeared or quit, and a
+/// `PlaybackStatus` change means another player may have become the one `find_active` prefers.
+/// It also keeps the watched player's position sample current: `Seeked` moves it, and a status,
+/// rate or track change discards it so the next query samples the player again. Capabilities
+/// are dropped on any property change of the watched player.
+struct PlayerWatcher {
+    connection: Connection,
+    stale: Arc<AtomicBool>,
+    watched_unique_name: Arc<Mutex<Option<String>>>,
+    position: Arc<Mutex<Option<PositionSample>>>,
+    capabilities: Arc<Mutex<Option<Capabilities>>>,
+}
+
+impl PlayerWatcher {
+    fn new() -> Result<PlayerWatcher, MediaControllerError> {
+        let connection = Connection::new_session()?;
+        let stale = Arc::new(AtomicBool::new(true));
+        let watched_unique_name: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+        let position: Arc<Mutex<Option<PositionSample>>> = Arc::new(Mutex::new(None));
+        let capabilities: Arc<Mutex<Option<Capabilities>>> = Arc::new(Mutex::new(None));
+
+        let name_owner_stale = stale.clone();
+        connection.add_match(
+            MatchRule::new_signal("org.freedesktop.DBus", "NameOwnerChanged")
+                .with_sender("org.freedesktop.DBus"),
+            move |(name, _, _): (String, String, String), _, _| {
+                if name.starts_with(MPRIS2_PREFIX) {
+                    name_owner_stale.store(true, Ordering::Release);
+                }
+                true
+            },
+        )?;
+
+        let status_stale = stale.clone();
+        let status_unique_name = watched_unique_name.clone();
+        let status_position = position.clone();
+        let status_capabilities = capabilities.clone();
+        connection.add_match(
+            MatchRule::new_signal(
+                PropertiesPropertiesChanged::INTERFACE,
+                PropertiesPropertiesChanged::NAME,
+            )
+            .with_path(MPRIS2_PATH),
+            move |changed: PropertiesPropertiesChanged, _, msg| {
+                let sender = msg.sender().map(|s| s.to_string());
+                let is_watched = *status_unique_name.lock().unwrap() == sender;
+
+                let status: Option<&String> =
+                    dbus::arg::prop_cast(&changed.changed_properties, "PlaybackStatus");
+                if let Some(status) = status {
+                    if is_watched != (status == "Playing") {
+                        status_stale.store(true, Ordering::Release);
+                    }
+                }
+
+                if is_watched
+                    && ["PlaybackStatus", "Rate", "Metadata"]
+                        .iter()
+                        .any(|p| changed.changed_properties.contains_key(*p))
+                {
+                    *status_position.lock().unwrap() = None;
+                }
+                if is_watched {
+                    *status_capabilities.lock().unwrap() = None;
+                }
+                true
+            },
+        )?;
+
+        let seeked_unique_name = watched_unique_name.clone();
+        let seeked_position = position.clone();
+        connection.add_match(
+            MatchRule::new_signal("org.mpris.MediaPlayer2.Player", "Seeked").with_path(MPRIS2_PATH),
+            move |(position_us,): (i64,), _, msg| {
+                let sender = msg.sender().map(|s| s.to_string());
+                if *seeked_unique_name.lock().unwrap() == sender {
+                    if let Some(sample) = seeked_position.lock().unwrap().as_mut() {
+                        sample.position = Duration::from_micros(position_us.max(0) as u64);
+                        sample.sampled_at = Instant::now();
+                    }
+                }
+                true
+            },
+        )?;
+
+        Ok(PlayerWatcher {
+            connection,
+            stale,
+            watched_unique_name,
+            position,
+            capabilities,
+        })
+    }
+
+    /// Dispatches every signal that is already queued, without blocking.
+    fn process_pending(&self) -> Result<(), MediaControllerError> {
+        while self.connection.process(Duration::ZERO)? {}
+        Ok(())
+    }
+
+    fn take_stale(&self) -> bool {
+        self.stale.swap(false, Ordering::AcqRel)
+    }
+
+    fn watch(&self, unique_name: &str) {
+        *self.watched_unique_name.lock().unwrap() = Some(unique_name.to_owned());
+        self.forget_position();
+        *self.capabilities.lock().unwrap() = None;
+    }
+
+    fn forget_position(&self) {
+        *self.position.lock().unwrap() = None;
+    }
+}
+
 pub struct MediaController {
-    player: mpris::PlayerFinder,
+    player_finder: mpris::PlayerFinder,
+    player: RefCell<Option<Rc<mpris::Player>>>,
+    /// Player the current call asked for by name, see [`MediaController::select_player`].
+    selected: RefCell<Option<String>>,
+    watcher: PlayerWatcher,
 }
 
 impl MediaController {
     pub fn new() -> Result<MediaController, MediaControllerError> {
         Ok(MediaController {
-            player: PlayerFinder::new().map_err(|e| MediaControllerError {
-                dbus_error: Some(e),
-                finding_error: None,
-            })?,
+            player_finder: PlayerFinder::new()?,
+            player: RefCell::new(None),
+            selected: RefCell::new(None),
+            watcher: PlayerWatcher::new()?,
         })
     }
 
+    pub fn is_connected(&self) -> bool {
+        self.watcher.connection.channel().is_connected()
+    }
+
+    /// Makes the following calls control the player with `name` as short bus name or
+    /// identity, e.g. `spotify` or `Spotify`, instead of the active one.
+    pub fn select_player(&self, name: Option<&str>) {
+        self.selected.replace(name.map(str::to_owned));
+    }
+
+    /// Returns the cached active player, looking it up again only after the watcher saw a
+    /// signal that could have changed it.
+    fn active_player(&self) -> Result<Rc<mpris::Player>, MediaControllerError> {
+        self.watcher.process_pending()?;
+        if self.watcher.take_stale() {
+            self.player.replace(None);
+        }
+        if let Some(name) = self.selected.borrow().as_deref() {
+            return self.named_player(name);
+        }
+
+        if let Some(player) = self.player.borrow().as_ref() {
+            return Ok(player.clone());
+        }
+
+        let player = Rc::new(self.player_finder.find_active()?);
+        self.watcher.watch(player.unique_name());
+        self.player.replace(Some(player.clone()));
+
+        Ok(player)
+    }
+
+    fn named_player(&self, name: &str) -> Result<Rc<mpris::Player>, MediaControllerError> {
+        let is_named = |player: &mpris::Player| {
+            player.bus_name_trimmed() == name || player.identity().eq_ignore_ascii_case(name)
+        };
+
+        if let Some(player) = self.player.borrow().as_ref().filter(|p| is_named(p)) {
+            return Ok(player.clone());
+        }
+
+        self.player_finder
+            .find_all()?
+            .into_iter()
+            .find(|player| is_named(player))
+            .map(Rc::new)
+            .ok_or_else(|| mpris::FindingError::NoPlayerFound.into())
+    }
+
+    /// Whether the watcher keeps the caches of `player` up to date.
+    fn is_watched(&self, player: &mpris::Player) -> bool {
+        self.watcher.watched_unique_name.lock().unwrap().as_deref() == Some(player.unique_name())
+    }
+
+    /// Returns the watched player's position sample, asking the player only when there is none.
+    fn position_sample(
+        &self,
+        player: &mpris::Player,
+    ) -> Result<PositionSample, MediaControllerError> {
+        let watched = self.is_watched(player);
+        if let Some(sample) = self.watcher.position.lock().unwrap().filter(|_| watched) {
+            return Ok(sample);
+        }
+
+        let sample = PositionSample {
+            position: player.get_position()?,
+            rate: player.checked_get_playback_rate()?.unwrap_or(1.0),
+            playing: player.get_playback_status()? == mpris::PlaybackStatus::Playing,
+            length: player.get_metadata()?.length(),
+            sampled_at: Instant::now(),
+        };
+        if watched {
+            *self.watcher.position.lock().unwrap() = Some(sample);
+        }
+
+        Ok(sample)
+    }
+
     pub fn media_pause(&self) -> Result<(), MediaControllerError> {
-        let player: mpris::Player =
-            self.player
-                .find_active()
-                .map_err(|e| MediaControllerError {
-                    finding_error: Some(e),
-                    dbus_error: None,
-                })?;
-
-        player.pause().map_err(|e| MediaControllerError {
-            dbus_error: Some(e),
-            finding_error: None,
-        })?;
+        let player = self.active_player()?;
+
+        player.pause()?;
 
         Ok(())
     }
 
     pub fn media_play(&self) -> Result<(), MediaControllerError> {
-        let player: mpris::Player =
-            self.player
-                .find_active()
-                .map_err(|e| MediaControllerError {
-                    finding_error: Some(e),
-                    dbus_error: None,
-                })?;
-
-        player.play().map_err(|e| MediaControllerError {
-            dbus_error: Some(e),
-            finding_error: None,
-        })?;
+        let player = self.active_player()?;
+
+        player.play()?;
 
         Ok(())
     }
 
     pub fn media_play_pause(&self) -> Result<(), MediaControllerError> {
-        let player: mpris::Player =
-            self.player
-                .find_active()
-                .map_err(|e| MediaControllerError {
-                    finding_error: Some(e),
-                    dbus_error: None,
-                })?;
-
-        player.play_pause().map_err(|e| MediaControllerError {
-            dbus_error: Some(e),
-            finding_error: None,
-        })?;
+        let player = self.active_player()?;
+
+        player.play_pause()?;
 
         Ok(())
     }
 
     pub fn media_get_title(&self) -> Result<String, MediaControllerError> {
-        let player: mpris::Player =
-            self.player
-                .find_active()
-                .map_err(|e| MediaControllerError {
-                    finding_error: Some(e),
-                    dbus_error: None,
-                })?;
-
-        let title = player
-            .get_metadata()
-            .map_err(|e| MediaControllerError {
-                dbus_error: Some(e),
-                finding_error: None,
-            })?
-            .title()
-            .unwrap_or("")
-            .to_owned();
+        let player = self.active_player()?;
+
+        let title = player.get_metadata()?.title().unwrap_or("").to_owned();
 
         Ok(title)
     }
 
     pub fn media_get_artist(&self) -> Result<String, MediaControllerError> {
-        let player: mpris::Player =
-            self.player
-                .find_active()
-                .map_err(|e| MediaControllerError {
-                    finding_error: Some(e),
-                    dbus_error: None,
-                })?;
+        let player = self.active_player()?;
 
         let artists = player
-            .get_metadata()
-            .map_err(|e| MediaControllerError {
-                dbus_error: Some(e),
-                finding_error: None,
-            })?
+            .get_metadata()?
             .artists()
             .map(|x| x.join(", "))
             .unwrap_or("".to_string());
@@ -117,54 +408,27 @@ impl MediaController {
     }
 
     pub fn media_get_art(&self) -> Result<Vec<u8>, MediaControllerError> {
-        let player: mpris::Player =
-            self.player
-                .find_active()
-                .map_err(|e| MediaControllerError {
-                    finding_error: Some(e),
-                    dbus_error: None,
-                })?;
-
-        let art_url = player
-            .get_metadata()
-            .map_err(|e| MediaControllerError {
-                dbus_error: Some(e),
-                finding_error: None,
-            })?
-            .art_url()
-            .unwrap_or("")
-            .to_owned();
-
-        println!("Art URL: {art_url}");
-
-        if art_url.starts_with("file://") {
-            let path = art_url.strip_prefix("file://").unwrap();
-            let path = urlencoding::decode(path).unwrap().into_owned();
-            let mut image_file = std::fs::File::open(path).unwrap();
-            let mut data = vec![];
-            std::io::Read::read_to_end(&mut image_file, &mut data).unwrap();
-
-            Ok(data)
+        let player = self.active_player()?;
+
+        let art_url = player.get_metadata()?.art_url().unwrap_or("").to_owned();
+
+        if let Some(path) = art_url.strip_prefix("file://") {
+            let path = urlencoding::decode(path).map_err(|_| {
+                MediaControllerError::failed(format!("Invalid cover art URL {art_url}"))
+            })?;
+            std::fs::read(path.as_ref()).map_err(|e| {
+                MediaControllerError::failed(format!("Could not read cover art {path}: {e}"))
+            })
         } else {
             Ok(art_url.as_bytes().to_owned())
         }
     }
 
     pub fn media_get_duration(&self) -> Result<f32, MediaControllerError> {
-        let player: mpris::Player =
-            self.player
-                .find_active()
-                .map_err(|e| MediaControllerError {
-                    finding_error: Some(e),
-                    dbus_error: None,
-                })?;
+        let player = self.active_player()?;
 
         let duration = player
-            .get_metadata()
-            .map_err(|e| MediaControllerError {
-                dbus_error: Some(e),
-                finding_error: None,
-            })?
+            .get_metadata()?
             .length()
             .unwrap_or(std::time::Duration::new(0, 0));
 
@@ -172,64 +436,145 @@ impl MediaController {
     }
 
     pub fn media_get_position(&self) -> Result<f32, MediaControllerError> {
-        let player: mpris::Player =
-            self.player
-                .find_active()
-                .map_err(|e| MediaControllerError {
-                    finding_error: Some(e),
-                    dbus_error: None,
-                })?;
-
-        let position = player.get_position().map_err(|e| MediaControllerError {
-            dbus_error: Some(e),
-            finding_error: None,
-        })?;
+        let player = self.active_player()?;
+
+        let position = self.position_sample(&player)?.position_at(Instant::now());
 
         Ok(position.as_secs_f32())
     }
 
+    /// Reads the player's capabilities with one `GetAll` per interface, reusing them until the
+    /// player reports a property change.
+    fn capabilities(&self, player: &mpris::Player) -> Result<Capabilities, MediaControllerError> {
+        let watched = self.is_watched(player);
+        if let Some(capabilities) = self
+            .watcher
+            .capabilities
+            .lock()
+            .unwrap()
+            .filter(|_| watched)
+        {
+            return Ok(capabilities);
+        }
+
+        let proxy =
+            self.watcher
+                .connection
+                .with_proxy(player.bus_name(), MPRIS2_PATH, PROPERTIES_TIMEOUT);
+        let root = proxy.get_all(MPRIS2_INTERFACE)?;
+        let props = proxy.get_all(MPRIS2_PLAYER_INTERFACE)?;
+        let flag = |name: &str| dbus::arg::prop_cast::<bool>(&props, name).copied();
+
+        let capabilities = Capabilities {
+            can_play: flag("CanPlay").unwrap_or(false),
+            can_pause: flag("CanPause").unwrap_or(false),
+            can_seek: flag("CanSeek").unwrap_or(false),
+            can_go_next: flag("CanGoNext").unwrap_or(false),
+            can_go_previous: flag("CanGoPrevious").unwrap_or(false),
+            can_control: flag("CanControl").unwrap_or(false),
+            has_volume: props.contains_key("Volume"),
+            has_shuffle: props.contains_key("Shuffle"),
+            has_track_list: dbus::arg::prop_cast::<bool>(&root, "HasTrackList")
+                .copied()
+                .unwrap_or(false),
+            min_rate: dbus::arg::prop_cast::<f64>(&props, "MinimumRate")
+                .copied()
+                .unwrap_or(1.0),
+            max_rate: dbus::arg::prop_cast::<f64>(&props, "MaximumRate")
+                .copied()
+                .unwrap_or(1.0),
+        };
+        if watched {
+            *self.watcher.capabilities.lock().unwrap() = Some(capabilities);
+        }
+
+        Ok(capabilities)
+    }
+
+    pub fn media_get_capabilities(&self) -> Result<Capabilities, MediaControllerError> {
+        let player = self.active_player()?;
+
+        self.capabilities(&player)
+    }
+
+    pub fn media_get_status(&self) -> Result<MediaStatus, MediaControllerError> {
+        let player = self.active_player()?;
+
+        let metadata = player.get_metadata()?;
+        let sample = self.position_sample(&player)?;
+        let capabilities = self.capabilities(&player)?;
+
+        Ok(MediaStatus {
+            player: player.identity().to_owned(),
+            title: metadata.title().unwrap_or("").to_owned(),
+            artist: metadata
+                .artists()
+                .map(|x| x.join(", "))
+                .unwrap_or("".to_string()),
+            album: metadata.album_name().unwrap_or("").to_owned(),
+            duration: metadata.length().unwrap_or_default().as_secs_f32(),
+            position: sample.position_at(Instant::now()).as_secs_f32(),
+            rate: sample.rate,
+            is_playing: sample.playing,
+            timestamp: unix_millis(SystemTime::now()),
+            capabilities,
+            metadata: metadata
+                .iter()
+                .map(|(key, value)| (key.to_owned(), metadata_json(value)))
+                .collect(),
+        })
+    }
+
+    pub fn media_get_players(&self) -> Result<Vec<Player>, MediaControllerError> {
+        let active = self.active_player().ok();
+
+        self.player_finder
+            .find_all()?
+            .iter()
+            .map(|player| {
+                Ok(Player {
+                    name: player.bus_name_trimmed().to_owned(),
+                    identity: player.identity().to_owned(),
+                    is_playing: player.get_playback_status()? == mpris::PlaybackStatus::Playing,
+                    is_active: active
+                        .as_ref()
+                        .is_some_and(|active| active.unique_name() == player.unique_name()),
+                })
+            })
+            .collect()
+    }
+
     pub fn media_set_position(&self, position: u64) -> Result<(), MediaControllerError> {
-        let player: mpris::Player =
-            self.player
-                .find_active()
-                .map_err(|e| MediaControllerError {
-                    finding_error: Some(e),
-                    dbus_error: None,
-                })?;
-
-        let metadata = player.get_metadata().map_err(|e| MediaControllerError {
-            dbus_error: Some(e),
-            finding_error: None,
-        })?;
+        let player = self.active_player()?;
 
-        //TODO: corectly handle lack of track_id
-        let track_id = metadata.track_id().unwrap();
+        let metadata = player.get_metadata()?;
 
-        player
-            .set_position(track_id, &std::time::Duration::from_secs(position))
-            .map_err(|e| MediaControllerError {
-                dbus_error: Some(e),
-                finding_error: None,
-            })?;
+        let track_id = metadata
+            .track_id()
+            .ok_or(MediaControllerError::unsupported(
+                "seeking in a track without an id",
+            ))?;
+
+        player.set_position(track_id, &std::time::Duration::from_secs(position))?;
+        self.watcher.forget_position();
+
+        Ok(())
+    }
+
+    /// Moves the position by `offset` seconds, backwards when negative.
+    pub fn media_seek(&self, offset: i64) -> Result<(), MediaControllerError> {
+        let player = self.active_player()?;
+
+        player.seek(offset * 1_000_000)?;
+        self.watcher.forget_position();
 
         Ok(())
     }
 
     pub fn media_is_playing(&self) -> Result<bool, MediaControllerError> {
-        let player: mpris::Player =
-            self.player
-                .find_active()
-                .map_err(|e| MediaControllerError {
-                    finding_error: Some(e),
-                    dbus_error: None,
-                })?;
-
-        let ps = player
-            .get_playback_status()
-            .map_err(|e| MediaControllerError {
-                dbus_error: Some(e),
-                finding_error: None,
-            })?;
+        let player = self.active_player()?;
+
+        let ps = player.get_playback_status()?;
 
         if ps == mpris::PlaybackStatus::Playing {
             Ok(true)
@@ -239,70 +584,451 @@ impl MediaController {
     }
 
     pub fn media_play_next(&self) -> Result<(), MediaControllerError> {
-        let player: mpris::Player =
-            self.player
-                .find_active()
-                .map_err(|e| MediaControllerError {
-                    finding_error: Some(e),
-                    dbus_error: None,
-                })?;
-
-        let metadata = player.get_metadata().map_err(|e| MediaControllerError {
-            dbus_error: Some(e),
-            finding_error: None,
-        })?;
+        let player = self.active_player()?;
+
+        let metadata = player.get_metadata()?;
 
         let duration = metadata
             .length()
             .unwrap_or(std::time::Duration::new(0, 0))
             .as_secs();
 
-        //TODO: corectly handle lack of track_id
-        let track_id = metadata.track_id().unwrap();
+        let track_id = metadata
+            .track_id()
+            .ok_or(MediaControllerError::unsupported(
+                "seeking in a track without an id",
+            ))?;
+        let near_end = duration
+            .checked_sub(2)
+            .ok_or(MediaControllerError::unsupported(
+                "skipping a track shorter than two seconds",
+            ))?;
 
-        player
-            .set_position(track_id, &(std::time::Duration::from_secs(duration - 2)))
-            .map_err(|e| MediaControllerError {
-                finding_error: None,
-                dbus_error: Some(e),
-            })?;
+        player.set_position(track_id, &std::time::Duration::from_secs(near_end))?;
 
         Ok(())
     }
 
     pub fn media_play_next2(&self) -> Result<(), MediaControllerError> {
-        let player: mpris::Player =
-            self.player
-                .find_active()
-                .map_err(|e| MediaControllerError {
-                    finding_error: Some(e),
-                    dbus_error: None,
-                })?;
-
-        player.checked_next().map_err(|e| MediaControllerError {
-            finding_error: None,
-            dbus_error: Some(e),
-        })?;
+        let player = self.active_player()?;
+
+        player.checked_next()?;
 
         Ok(())
     }
 
     pub fn media_play_prev(&self) -> Result<(), MediaControllerError> {
-        let player: mpris::Player =
-            self.player
-                .find_active()
-                .map_err(|e| MediaControllerError {
-                    finding_error: Some(e),
-                    dbus_error: None,
-                })?;
-
-        player
-            .checked_previous()
-            .map_err(|e| MediaControllerError {
-                finding_error: None,
-                dbus_error: Some(e),
-            })?;
+        let player = self.active_player()?;
+
+        player.checked_previous()?;
+
+        Ok(())
+    }
+
+    pub fn media_get_volume(&self) -> Result<f64, MediaControllerError> {
+        let player = self.active_player()?;
+        if !self.capabilities(&player)?.has_volume {
+            return Err(MediaControllerError::unsupported("volume"));
+        }
+
+        Ok(player.get_volume()?)
+    }
+
+    /// Sets the volume, where 1.0 is the player's full volume.
+    pub fn media_set_volume(&self, volume: f64) -> Result<(), MediaControllerError> {
+        if !(0.0..=1.0).contains(&volume) {
+            return Err(MediaControllerError::invalid_argument(format!(
+                "Volume {volume} is not between 0 and 1"
+            )));
+        }
+        let player = self.active_player()?;
+        let capabilities = self.capabilities(&player)?;
+        if !capabilities.has_volume || !capabilities.can_control {
+            return Err(MediaControllerError::unsupported("volume"));
+        }
+
+        player.set_volume(volume)?;
+
+        Ok(())
+    }
+
+    pub fn media_stop(&self) -> Result<(), MediaControllerError> {
+        let player = self.active_player()?;
+
+        if !player.checked_stop()? {
+            return Err(MediaControllerError::unsupported("stop"));
+        }
+
+        Ok(())
+    }
+
+    pub fn media_raise(&self) -> Result<(), MediaControllerError> {
+        let player = self.active_player()?;
+
+        if !player.checked_raise()? {
+            return Err(MediaControllerError::unsupported("raise"));
+        }
+
+        Ok(())
+    }
+
+    pub fn media_quit(&self) -> Result<(), MediaControllerError> {
+        let player = self.active_player()?;
+
+        if !player.checked_quit()? {
+            return Err(MediaControllerError::unsupported("quit"));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the active player if it exposes a tracklist.
+    fn track_list_player(&self) -> Result<Rc<mpris::Player>, MediaControllerError> {
+        let player = self.active_player()?;
+
+        if !player.supports_track_lists() || !player.get_has_track_list()? {
+            return Err(MediaControllerError::unsupported("tracklists"));
+        }
+
+        Ok(player)
+    }
+
+    pub fn media_get_track_list(&self) -> Result<TrackList, MediaControllerError> {
+        let player = self.track_list_player()?;
+
+        let track_list = player.get_track_list()?;
+        let current_track_id = player.get_metadata()?.track_id();
+        let tracks_metadata = if track_list.is_empty() {
+            vec![]
+        } else {
+            player.get_tracks_metadata(track_list.ids())?
+        };
+
+        let tracks = tracks_metadata
+            .iter()
+            .map(|metadata| {
+                let track_id = metadata.track_id();
+                Track {
+                    id: track_id
+                        .as_ref()
+                        .map(|id| id.to_string())
+                        .unwrap_or_default(),
+                    title: metadata.title().unwrap_or("").to_owned(),
+                    artist: metadata
+                        .artists()
+                        .map(|x| x.join(", "))
+                        .unwrap_or("".to_string()),
+                    album: metadata.album_name().unwrap_or("").to_owned(),
+                    duration: metadata.length().unwrap_or_default().as_secs_f32(),
+                    url: metadata.url().unwrap_or("").to_owned(),
+                    is_current: track_id.is_some() && track_id == current_track_id,
+                }
+            })
+            .collect();
+
+        Ok(TrackList {
+            can_edit_tracks: player.can_edit_tracks()?,
+            tracks,
+        })
+    }
+
+    pub fn media_go_to(&self, track_id: &str) -> Result<(), MediaControllerError> {
+        let player = self.track_list_player()?;
+
+        player.go_to(&parse_track_id(track_id)?)?;
 
         Ok(())
     }
+
+    /// Adds `uri` after `after`, or at the start of the tracklist when it is `None`.
+    pub fn media_add_track(
+        &self,
+        uri: &str,
+        after: Option<&str>,
+        set_as_current: bool,
+    ) -> Result<(), MediaControllerError> {
+        let player = self.track_list_player()?;
+        if !player.can_edit_tracks()? {
+            return Err(MediaControllerError::unsupported("editing the tracklist"));
+        }
+
+        match after {
+            Some(after) => player.add_track(uri, &parse_track_id(after)?, set_as_current)?,
+            None => player.add_track_at_start(uri, set_as_current)?,
+        }
+
+        Ok(())
+    }
+
+    pub fn media_remove_track(&self, track_id: &str) -> Result<(), MediaControllerError> {
+        let player = self.track_list_player()?;
+        if !player.can_edit_tracks()? {
+            return Err(MediaControllerError::unsupported("editing the tracklist"));
+        }
+
+        player.remove_track(&parse_track_id(track_id)?)?;
+
+        Ok(())
+    }
+
+    /// Lists the player's playlists sorted by `order`, one of the player's `Orderings`, or by
+    /// the first ordering it offers when `None`.
+    ///
+    /// The mpris crate does not expose the Playlists interface, so it is called directly on the
+    /// player's bus name.
+    pub fn media_get_playlists(
+        &self,
+        order: Option<&str>,
+        reverse_order: bool,
+    ) -> Result<Playlists, MediaControllerError> {
+        let player = self.active_player()?;
+
+        let proxy =
+            self.watcher
+                .connection
+                .with_proxy(player.bus_name(), MPRIS2_PATH, PROPERTIES_TIMEOUT);
+        let props = proxy
+            .get_all(MPRIS2_PLAYLISTS_INTERFACE)
+            .map_err(|e| unsupported_interface(e, "playlists"))?;
+        if props.is_empty() {
+            return Err(MediaControllerError::unsupported("playlists"));
+        }
+
+        let orderings: Vec<String> = dbus::arg::prop_cast(&props, "Orderings")
+            .cloned()
+            .unwrap_or_default();
+        let order = match order {
+            Some(order) if orderings.iter().any(|o| o == order) => order.to_owned(),
+            Some(order) => {
+                return Err(MediaControllerError::invalid_argument(format!(
+                    "Unsupported playlist ordering {order}, expected one of: {}",
+                    orderings.join(", ")
+                )))
+            }
+            None => orderings
+                .first()
+                .cloned()
+                .unwrap_or("Alphabetical".to_string()),
+        };
+        let count = dbus::arg::prop_cast::<u32>(&props, "PlaylistCount")
+            .copied()
+            .unwrap_or(0);
+
+        let playlists = if count == 0 {
+            vec![]
+        } else {
+            let (playlists,): (Vec<(dbus::Path<'static>, String, String)>,) = proxy.method_call(
+                MPRIS2_PLAYLISTS_INTERFACE,
+                "GetPlaylists",
+                (0u32, count, order.as_str(), reverse_order),
+            )?;
+            playlists.into_iter().map(to_playlist).collect()
+        };
+
+        let (has_active, active): (bool, (dbus::Path<'static>, String, String)) =
+            proxy.get(MPRIS2_PLAYLISTS_INTERFACE, "ActivePlaylist")?;
+
+        Ok(Playlists {
+            orderings,
+            active: has_active.then(|| to_playlist(active)),
+            playlists,
+        })
+    }
+
+    pub fn media_activate_playlist(&self, playlist_id: &str) -> Result<(), MediaControllerError> {
+        let player = self.active_player()?;
+
+        let playlist_id =
+            dbus::Path::new(playlist_id).map_err(MediaControllerError::invalid_argument)?;
+        let proxy =
+            self.watcher
+                .connection
+                .with_proxy(player.bus_name(), MPRIS2_PATH, PROPERTIES_TIMEOUT);
+        proxy
+            .method_call::<(), _, _, _>(
+                MPRIS2_PLAYLISTS_INTERFACE,
+                "ActivatePlaylist",
+                (playlist_id,),
+            )
+            .map_err(|e| unsupported_interface(e, "playlists"))?;
+
+        Ok(())
+    }
+
+    /// Asks the player to open `uri`, refusing schemes missing from its `SupportedUriSchemes`
+    /// and, when the type can be guessed from the path, types missing from `SupportedMimeTypes`.
+    pub fn media_open_uri(&self, uri: &url::Url) -> Result<(), MediaControllerError> {
+        let player = self.active_player()?;
+
+        let schemes = player.get_supported_uri_schemes()?;
+        if !schemes.iter().any(|s| s.eq_ignore_ascii_case(uri.scheme())) {
+            return Err(MediaControllerError::invalid_argument(format!(
+                "The player does not open {} URIs, supported: {}",
+                uri.scheme(),
+                schemes.join(", ")
+            )));
+        }
+
+        if let Some(mime) = mime_guess::from_path(uri.path()).first() {
+            let mime_types = player.get_supported_mime_types()?;
+            if !mime_types.is_empty() && !mime_types.iter().any(|m| m == mime.essence_str()) {
+                return Err(MediaControllerError::invalid_argument(format!(
+                    "The player does not open {} media",
+                    mime.essence_str()
+                )));
+            }
+        }
+
+        let proxy =
+            self.watcher
+                .connection
+                .with_proxy(player.bus_name(), MPRIS2_PATH, PROPERTIES_TIMEOUT);
+        proxy.method_call::<(), _, _, _>(MPRIS2_PLAYER_INTERFACE, "OpenUri", (uri.as_str(),))?;
+
+        Ok(())
+    }
+}
+
+fn to_playlist((id, name, icon): (dbus::Path<'static>, String, String)) -> Playlist {
+    Playlist {
+        id: id.to_string(),
+        name,
+        icon,
+    }
+}
+
+/// Maps the errors a player returns for an interface it does not implement to `unsupported`.
+fn unsupported_interface(e: dbus::Error, operation: &'static str) -> MediaControllerError {
+    match e.name() {
+        Some(
+            "org.freedesktop.DBus.Error.UnknownInterface"
+            | "org.freedesktop.DBus.Error.UnknownMethod"
+            | "org.freedesktop.DBus.Error.InvalidArgs",
+        ) => MediaControllerError::unsupported(operation),
+        _ => MediaControllerError::from(e),
+    }
+}
+
+fn metadata_json(value: &mpris::MetadataValue) -> serde_json::Value {
+    use mpris::MetadataValue;
+
+    match value {
+        MetadataValue::String(text) => text.as_str().into(),
+        MetadataValue::I16(n) => (*n).into(),
+        MetadataValue::I32(n) => (*n).into(),
+        MetadataValue::I64(n) => (*n).into(),
+        MetadataValue::U8(n) => (*n).into(),
+        MetadataValue::U16(n) => (*n).into(),
+        MetadataValue::U32(n) => (*n).into(),
+        MetadataValue::U64(n) => (*n).into(),
+        MetadataValue::F64(n) => (*n).into(),
+        MetadataValue::Bool(b) => (*b).into(),
+        MetadataValue::Array(items) => items.iter().map(metadata_json).collect(),
+        MetadataValue::Map(map) => map
+            .iter()
+            .map(|(key, value)| (key.clone(), metadata_json(value)))
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+        MetadataValue::Unsupported => serde_json::Value::Null,
+    }
+}
+
+fn parse_track_id(track_id: &str) -> Result<mpris::TrackID, MediaControllerError> {
+    mpris::TrackID::new(track_id).map_err(MediaControllerError::invalid_argument)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(position: u64, rate: f64, playing: bool, length: Option<u64>) -> PositionSample {
+        PositionSample {
+            position: Duration::from_secs(position),
+            rate,
+            playing,
+            length: length.map(Duration::from_secs),
+            sampled_at: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn position_moves_with_the_rate_while_playing() {
+        let sample = sample(10, 1.5, true, Some(300));
+        let now = sample.sampled_at + Duration::from_secs(4);
+
+        assert_eq!(sample.position_at(now), Duration::from_secs(16));
+    }
+
+    #[test]
+    fn position_stays_while_paused_or_stopped_by_rate() {
+        for sample in [
+            sample(10, 1.0, false, Some(300)),
+            sample(10, 0.0, true, Some(300)),
+        ] {
+            let now = sample.sampled_at + Duration::from_secs(4);
+
+            assert_eq!(sample.position_at(now), Duration::from_secs(10));
+        }
+    }
+
+    #[test]
+    fn position_stops_at_the_end_of_the_track() {
+        let sample = sample(295, 1.0, true, Some(300));
+        let now = sample.sampled_at + Duration::from_secs(60);
+
+        assert_eq!(sample.position_at(now), Duration::from_secs(300));
+    }
+
+    #[test]
+    fn position_is_not_capped_without_a_length() {
+        for sample in [
+            sample(295, 1.0, true, None),
+            sample(295, 1.0, true, Some(0)),
+        ] {
+            let now = sample.sampled_at + Duration::from_secs(60);
+
+            assert_eq!(sample.position_at(now), Duration::from_secs(355));
+        }
+    }
+
+    #[test]
+    fn position_does_not_go_back_for_an_earlier_instant() {
+        let sample = sample(10, 1.0, true, None);
+        let earlier = sample.sampled_at - Duration::from_millis(1);
+
+        assert_eq!(sample.position_at(earlier), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn metadata_values_become_json() {
+        use mpris::MetadataValue;
+
+        let map = MetadataValue::Map(
+            [("rating".to_string(), MetadataValue::F64(0.5))]
+                .into_iter()
+                .collect(),
+        );
+        let values = [
+            (
+                MetadataValue::String("Song".to_string()),
+                serde_json::json!("Song"),
+            ),
+            (
+                MetadataValue::I64(180_000_000),
+                serde_json::json!(180_000_000),
+            ),
+            (MetadataValue::U8(3), serde_json::json!(3)),
+            (MetadataValue::Bool(true), serde_json::json!(true)),
+            (
+                MetadataValue::Array(vec!["A".into(), "B".into()]),
+                serde_json::json!(["A", "B"]),
+            ),
+            (map, serde_json::json!({"rating": 0.5})),
+            (MetadataValue::Unsupported, serde_json::Value::Null),
+        ];
+
+        for (value, json) in values {
+            assert_eq!(metadata_json(&value), json);
+        }
+    }
 }
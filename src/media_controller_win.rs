@@ -1,18 +1,170 @@
+use crate::media_status::{unix_millis, Capabilities, MediaStatus, Player, Playlists, TrackList};
 use futures::executor::block_on;
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 use windows::{
-    Media,
+    Foundation::TypedEventHandler,
+    Media::Control::{
+        GlobalSystemMediaTransportControlsSession,
+        GlobalSystemMediaTransportControlsSessionManager,
+        GlobalSystemMediaTransportControlsSessionMediaProperties,
+        GlobalSystemMediaTransportControlsSessionTimelineProperties,
+    },
     Storage::Streams::{Buffer, DataReader, InputStreamOptions},
 };
 
-#[derive(Debug)]
-pub struct MediaControllerError {}
+/// 100ns ticks between 1601-01-01, the epoch of WinRT `DateTime`, and the Unix epoch.
+const UNIX_EPOCH_TICKS: i64 = 116_444_736_000_000_000;
+
+#[derive(Debug, Default)]
+pub struct MediaControllerError {
+    unsupported: Option<&'static str>,
+    invalid_argument: Option<String>,
+    no_session: bool,
+}
+
+impl MediaControllerError {
+    /// The current session does not allow `operation`.
+    pub fn unsupported(operation: &'static str) -> Self {
+        MediaControllerError {
+            unsupported: Some(operation),
+            invalid_argument: None,
+            no_session: false,
+        }
+    }
+
+    /// The request carried a value the session cannot accept.
+    pub fn invalid_argument(message: String) -> Self {
+        MediaControllerError {
+            unsupported: None,
+            invalid_argument: Some(message),
+            no_session: false,
+        }
+    }
+
+    /// No media session is open, or none matches the requested app.
+    pub fn no_session() -> Self {
+        MediaControllerError {
+            unsupported: None,
+            invalid_argument: None,
+            no_session: true,
+        }
+    }
+
+    pub fn is_unsupported(&self) -> bool {
+        self.unsupported.is_some()
+    }
+
+    pub fn is_invalid_argument(&self) -> bool {
+        self.invalid_argument.is_some()
+    }
+
+    pub fn is_no_player(&self) -> bool {
+        self.no_session
+    }
+}
+
+impl std::fmt::Display for MediaControllerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.unsupported, &self.invalid_argument) {
+            (Some(operation), _) => write!(f, "The current session does not support {operation}"),
+            (None, Some(message)) => write!(f, "{message}"),
+            (None, None) if self.no_session => write!(f, "No media session found"),
+            (None, None) => write!(f, "Media session request failed"),
+        }
+    }
+}
+
+/// A failed WinRT call, whose HRESULT says little more to clients than the request failing.
+impl From<windows::core::Error> for MediaControllerError {
+    fn from(_: windows::core::Error) -> Self {
+        MediaControllerError::default()
+    }
+}
 
 #[derive(Debug)]
-pub struct MediaController {}
+pub struct MediaController {
+    session_manager: GlobalSystemMediaTransportControlsSessionManager,
+    session: Arc<Mutex<Option<GlobalSystemMediaTransportControlsSession>>>,
+    /// App the current call asked for, see [`MediaController::select_player`].
+    selected: RefCell<Option<String>>,
+    current_session_changed_token: i64,
+}
 
 impl MediaController {
     pub fn new() -> Result<MediaController, MediaControllerError> {
-        Ok(MediaController {})
+        let session_manager = block_on(async {
+            GlobalSystemMediaTransportControlsSessionManager::RequestAsync()
+                .map_err(|_| MediaControllerError::default())?
+                .await
+                .map_err(|_| MediaControllerError::default())
+        })?;
+
+        let session: Arc<Mutex<Option<GlobalSystemMediaTransportControlsSession>>> =
+            Arc::new(Mutex::new(None));
+        let changed_session = session.clone();
+        let current_session_changed_token = session_manager
+            .CurrentSessionChanged(&TypedEventHandler::new(move |_, _| {
+                *changed_session.lock().unwrap() = None;
+                Ok(())
+            }))
+            .map_err(|_| MediaControllerError::default())?;
+
+        Ok(MediaController {
+            session_manager,
+            session,
+            selected: RefCell::new(None),
+            current_session_changed_token,
+        })
+    }
+
+    /// Whether the session manager still answers, it stops when the media service restarts.
+    pub fn is_connected(&self) -> bool {
+        self.session_manager.GetSessions().is_ok()
+    }
+
+    /// Makes the following calls control the session of the app with `name` as app id instead
+    /// of the current session.
+    pub fn select_player(&self, name: Option<&str>) {
+        self.selected.replace(name.map(str::to_owned));
+    }
+
+    /// Returns the cached current session, asking the session manager again only after
+    /// `CurrentSessionChanged` fired.
+    fn session(&self) -> Result<GlobalSystemMediaTransportControlsSession, MediaControllerError> {
+        if let Some(name) = self.selected.borrow().as_deref() {
+            return self.named_session(name);
+        }
+
+        let mut session = self.session.lock().unwrap();
+        if let Some(session) = session.as_ref() {
+            return Ok(session.clone());
+        }
+
+        let current_session = self
+            .session_manager
+            .GetCurrentSession()
+            .map_err(|_| MediaControllerError::no_session())?;
+        *session = Some(current_session.clone());
+
+        Ok(current_session)
+    }
+
+    fn named_session(
+        &self,
+        name: &str,
+    ) -> Result<GlobalSystemMediaTransportControlsSession, MediaControllerError> {
+        self.session_manager
+            .GetSessions()
+            .map_err(|_| MediaControllerError::default())?
+            .into_iter()
+            .find(|session| {
+                session
+                    .SourceAppUserModelId()
+                    .is_ok_and(|id| format!("{}", id).eq_ignore_ascii_case(name))
+            })
+            .ok_or_else(MediaControllerError::no_session)
     }
 
     pub fn media_pause(&self) -> Result<(), MediaControllerError> {
@@ -20,14 +172,9 @@ impl MediaController {
     }
 
     async fn _media_pause(&self) -> Result<(), MediaControllerError> {
-        let session_manager =
-            Media::Control::GlobalSystemMediaTransportControlsSessionManager::RequestAsync()
-                .unwrap()
-                .await
-                .unwrap();
-        let session = session_manager.GetCurrentSession().unwrap();
+        let session = self.session()?;
 
-        session.TryPauseAsync().unwrap().await.unwrap();
+        session.TryPauseAsync()?.await?;
         Ok(())
     }
 
@@ -36,14 +183,9 @@ impl MediaController {
     }
 
     async fn _media_play(&self) -> Result<(), MediaControllerError> {
-        let session_manager =
-            Media::Control::GlobalSystemMediaTransportControlsSessionManager::RequestAsync()
-                .unwrap()
-                .await
-                .unwrap();
-        let session = session_manager.GetCurrentSession().unwrap();
+        let session = self.session()?;
 
-        session.TryPlayAsync().unwrap().await.unwrap();
+        session.TryPlayAsync()?.await?;
         Ok(())
     }
 
@@ -52,14 +194,9 @@ impl MediaController {
     }
 
     async fn _media_play_pause(&self) -> Result<(), MediaControllerError> {
-        let session_manager =
-            Media::Control::GlobalSystemMediaTransportControlsSessionManager::RequestAsync()
-                .unwrap()
-                .await
-                .unwrap();
-        let session = session_manager.GetCurrentSession().unwrap();
+        let session = self.session()?;
 
-        session.TryTogglePlayPauseAsync().unwrap().await.unwrap();
+        session.TryTogglePlayPauseAsync()?.await?;
         Ok(())
     }
 
@@ -69,20 +206,13 @@ impl MediaController {
     }
 
     async fn _media_play_next(&self, duration: f32) -> Result<(), MediaControllerError> {
-        let session_manager =
-            Media::Control::GlobalSystemMediaTransportControlsSessionManager::RequestAsync()
-                .unwrap()
-                .await
-                .unwrap();
-        let session = session_manager.GetCurrentSession().unwrap();
+        let session = self.session()?;
 
-        session.TrySkipNextAsync().unwrap().await.unwrap();
+        session.TrySkipNextAsync()?.await?;
         let possition = (duration - 2.0) as i64;
         session
-            .TryChangePlaybackPositionAsync(possition * 10_000_000)
-            .unwrap()
-            .await
-            .unwrap();
+            .TryChangePlaybackPositionAsync(possition * 10_000_000)?
+            .await?;
         Ok(())
     }
 
@@ -91,31 +221,95 @@ impl MediaController {
     }
 
     async fn _media_play_prev(&self) -> Result<(), MediaControllerError> {
-        let session_manager =
-            Media::Control::GlobalSystemMediaTransportControlsSessionManager::RequestAsync()
-                .unwrap()
-                .await
-                .unwrap();
-        let session = session_manager.GetCurrentSession().unwrap();
+        let session = self.session()?;
 
-        session.TrySkipPreviousAsync().unwrap().await.unwrap();
+        session.TrySkipPreviousAsync()?.await?;
         Ok(())
     }
 
+    /// GSMTC has no per-session volume.
+    pub fn media_get_volume(&self) -> Result<f64, MediaControllerError> {
+        Err(MediaControllerError::unsupported("volume"))
+    }
+
+    pub fn media_set_volume(&self, _volume: f64) -> Result<(), MediaControllerError> {
+        Err(MediaControllerError::unsupported("volume"))
+    }
+
+    pub fn media_stop(&self) -> Result<(), MediaControllerError> {
+        block_on(self._media_stop())
+    }
+
+    async fn _media_stop(&self) -> Result<(), MediaControllerError> {
+        let session = self.session()?;
+        let controls = session.GetPlaybackInfo()?.Controls()?;
+        if !controls.IsStopEnabled().unwrap_or(false) {
+            return Err(MediaControllerError::unsupported("stop"));
+        }
+
+        session.TryStopAsync()?.await?;
+        Ok(())
+    }
+
+    /// GSMTC has no way to bring the session's window to the front.
+    pub fn media_raise(&self) -> Result<(), MediaControllerError> {
+        Err(MediaControllerError::unsupported("raise"))
+    }
+
+    /// GSMTC has no way to close the application owning a session.
+    pub fn media_quit(&self) -> Result<(), MediaControllerError> {
+        Err(MediaControllerError::unsupported("quit"))
+    }
+
+    /// GSMTC does not expose the session's queue.
+    pub fn media_get_track_list(&self) -> Result<TrackList, MediaControllerError> {
+        Err(MediaControllerError::unsupported("tracklists"))
+    }
+
+    pub fn media_go_to(&self, _track_id: &str) -> Result<(), MediaControllerError> {
+        Err(MediaControllerError::unsupported("tracklists"))
+    }
+
+    pub fn media_add_track(
+        &self,
+        _uri: &str,
+        _after: Option<&str>,
+        _set_as_current: bool,
+    ) -> Result<(), MediaControllerError> {
+        Err(MediaControllerError::unsupported("tracklists"))
+    }
+
+    pub fn media_remove_track(&self, _track_id: &str) -> Result<(), MediaControllerError> {
+        Err(MediaControllerError::unsupported("tracklists"))
+    }
+
+    /// GSMTC does not expose the session's playlists.
+    pub fn media_get_playlists(
+        &self,
+        _order: Option<&str>,
+        _reverse_order: bool,
+    ) -> Result<Playlists, MediaControllerError> {
+        Err(MediaControllerError::unsupported("playlists"))
+    }
+
+    pub fn media_activate_playlist(&self, _playlist_id: &str) -> Result<(), MediaControllerError> {
+        Err(MediaControllerError::unsupported("playlists"))
+    }
+
+    /// GSMTC can only control sessions, not hand them new media.
+    pub fn media_open_uri(&self, _uri: &url::Url) -> Result<(), MediaControllerError> {
+        Err(MediaControllerError::unsupported("opening URIs"))
+    }
+
     pub fn media_get_title(&self) -> Result<String, MediaControllerError> {
         block_on(self._media_get_title())
     }
 
     async fn _media_get_title(&self) -> Result<String, MediaControllerError> {
-        let session_manager =
-            Media::Control::GlobalSystemMediaTransportControlsSessionManager::RequestAsync()
-                .unwrap()
-                .await
-                .unwrap();
-        let session = session_manager.GetCurrentSession().unwrap();
-        let sesiion_media_properties = session.TryGetMediaPropertiesAsync().unwrap().await.unwrap();
+        let session = self.session()?;
+        let sesiion_media_properties = session.TryGetMediaPropertiesAsync()?.await?;
 
-        Ok(format!("{}", sesiion_media_properties.Title().unwrap()))
+        Ok(format!("{}", sesiion_media_properties.Title()?))
     }
 
     pub fn media_get_duration(&self) -> Result<f32, MediaControllerError> {
@@ -123,14 +317,9 @@ impl MediaController {
     }
 
     async fn _media_get_duration(&self) -> Result<f32, MediaControllerError> {
-        let session_manager =
-            Media::Control::GlobalSystemMediaTransportControlsSessionManager::RequestAsync()
-                .unwrap()
-                .await
-                .unwrap();
-        let session = session_manager.GetCurrentSession().unwrap();
-        let timeline_properties = session.GetTimelineProperties().unwrap();
-        let end_time = timeline_properties.EndTime().unwrap();
+        let session = self.session()?;
+        let timeline_properties = session.GetTimelineProperties()?;
+        let end_time = timeline_properties.EndTime()?;
         let duration = end_time.Duration as f64 / 10_000_000.0;
 
         Ok(duration as f32)
@@ -141,37 +330,128 @@ impl MediaController {
     }
 
     async fn _media_get_position(&self) -> Result<f32, MediaControllerError> {
-        let session_manager =
-            Media::Control::GlobalSystemMediaTransportControlsSessionManager::RequestAsync()
-                .unwrap()
-                .await
-                .unwrap();
-        let session = session_manager.GetCurrentSession().unwrap();
-        let timeline_properties = session.GetTimelineProperties().unwrap();
-        let position = timeline_properties.Position().unwrap();
-        let position = position.Duration as f64 / 10_000_000.0;
+        let session = self.session()?;
+        let timeline_properties = session.GetTimelineProperties()?;
+        let playback_info = session.GetPlaybackInfo()?;
+        let is_playing = playback_info.PlaybackStatus()?.0 == 4;
+        let rate = playback_info
+            .PlaybackRate()
+            .and_then(|rate| rate.Value())
+            .unwrap_or(1.0);
+        let position =
+            estimate_position(&timeline_properties, is_playing, rate, SystemTime::now())?;
 
         Ok(position as f32)
     }
 
+    pub fn media_get_capabilities(&self) -> Result<Capabilities, MediaControllerError> {
+        let session = self.session()?;
+
+        capabilities(&session)
+    }
+
+    pub fn media_get_status(&self) -> Result<MediaStatus, MediaControllerError> {
+        block_on(self._media_get_status())
+    }
+
+    async fn _media_get_status(&self) -> Result<MediaStatus, MediaControllerError> {
+        let session = self.session()?;
+        let sesiion_media_properties = session.TryGetMediaPropertiesAsync()?.await?;
+        let timeline_properties = session.GetTimelineProperties()?;
+        let playback_info = session.GetPlaybackInfo()?;
+        let is_playing = playback_info.PlaybackStatus()?.0 == 4;
+        let rate = playback_info
+            .PlaybackRate()
+            .and_then(|rate| rate.Value())
+            .unwrap_or(1.0);
+        let duration = timeline_properties.EndTime()?.Duration as f64 / 10_000_000.0;
+        let now = SystemTime::now();
+        let title = format!("{}", sesiion_media_properties.Title()?);
+        let artist = sesiion_media_properties
+            .Artist()
+            .map(|artist| format!("{}", artist))
+            .unwrap_or_default();
+        let album = sesiion_media_properties
+            .AlbumTitle()
+            .map(|album| format!("{}", album))
+            .unwrap_or_default();
+        let metadata = metadata(&sesiion_media_properties, &title, &artist, &album, duration);
+
+        Ok(MediaStatus {
+            player: session
+                .SourceAppUserModelId()
+                .map(|id| format!("{}", id))
+                .unwrap_or_default(),
+            title,
+            artist,
+            album,
+            duration: duration as f32,
+            position: estimate_position(&timeline_properties, is_playing, rate, now)? as f32,
+            rate,
+            is_playing,
+            timestamp: unix_millis(now),
+            capabilities: capabilities(&session)?,
+            metadata,
+        })
+    }
+
+    pub fn media_get_players(&self) -> Result<Vec<Player>, MediaControllerError> {
+        let active = self
+            .session()
+            .ok()
+            .and_then(|session| session.SourceAppUserModelId().ok())
+            .map(|id| format!("{}", id));
+        let sessions = self
+            .session_manager
+            .GetSessions()
+            .map_err(|_| MediaControllerError::default())?;
+
+        Ok(sessions
+            .into_iter()
+            .map(|session| {
+                let name = session
+                    .SourceAppUserModelId()
+                    .map(|id| format!("{}", id))
+                    .unwrap_or_default();
+                Player {
+                    identity: name.clone(),
+                    is_playing: session
+                        .GetPlaybackInfo()
+                        .and_then(|info| info.PlaybackStatus())
+                        .is_ok_and(|status| status.0 == 4),
+                    is_active: active.as_ref() == Some(&name),
+                    name,
+                }
+            })
+            .collect())
+    }
+
     pub fn media_set_position(&self, position: u64) -> Result<(), MediaControllerError> {
         block_on(self._media_set_position(position))
     }
 
     async fn _media_set_position(&self, position: u64) -> Result<(), MediaControllerError> {
-        let session_manager =
-            Media::Control::GlobalSystemMediaTransportControlsSessionManager::RequestAsync()
-                .unwrap()
-                .await
-                .unwrap();
-        let session = session_manager.GetCurrentSession().unwrap();
+        let session = self.session()?;
 
         let possition = position as i64;
         session
-            .TryChangePlaybackPositionAsync(possition * 10_000_000)
-            .unwrap()
-            .await
-            .unwrap();
+            .TryChangePlaybackPositionAsync(possition * 10_000_000)?
+            .await?;
+        Ok(())
+    }
+
+    /// Moves the position by `offset` seconds, backwards when negative.
+    pub fn media_seek(&self, offset: i64) -> Result<(), MediaControllerError> {
+        block_on(self._media_seek(offset))
+    }
+
+    async fn _media_seek(&self, offset: i64) -> Result<(), MediaControllerError> {
+        let position = self._media_get_position().await? as f64 + offset as f64;
+
+        let session = self.session()?;
+        session
+            .TryChangePlaybackPositionAsync((position.max(0.0) * 10_000_000.0) as i64)?
+            .await?;
         Ok(())
     }
 
@@ -180,13 +460,8 @@ impl MediaController {
     }
 
     async fn _media_is_playing(&self) -> Result<bool, MediaControllerError> {
-        let session_manager =
-            Media::Control::GlobalSystemMediaTransportControlsSessionManager::RequestAsync()
-                .unwrap()
-                .await
-                .unwrap();
-        let session = session_manager.GetCurrentSession().unwrap();
-        let playback_status = session.GetPlaybackInfo().unwrap().PlaybackStatus().unwrap();
+        let session = self.session()?;
+        let playback_status = session.GetPlaybackInfo()?.PlaybackStatus()?;
 
         Ok(playback_status.0 == 4)
     }
@@ -196,38 +471,28 @@ impl MediaController {
     }
 
     async fn _media_get_art(&self) -> Result<Vec<u8>, MediaControllerError> {
-        let session_manager =
-            Media::Control::GlobalSystemMediaTransportControlsSessionManager::RequestAsync()
-                .unwrap()
-                .await
-                .unwrap();
-        let session = session_manager.GetCurrentSession().unwrap();
-        let sesiion_media_properties = session.TryGetMediaPropertiesAsync().unwrap().await.unwrap();
-
-        let thumbnail = sesiion_media_properties.Thumbnail().unwrap();
-        let stream = thumbnail.OpenReadAsync().unwrap().await.unwrap();
+        let session = self.session()?;
+        let sesiion_media_properties = session.TryGetMediaPropertiesAsync()?.await?;
 
-        let size: u32 = stream.Size().unwrap().try_into().unwrap();
-        let buffer = Buffer::Create(size).unwrap();
+        let thumbnail = sesiion_media_properties.Thumbnail()?;
+        let stream = thumbnail.OpenReadAsync()?.await?;
 
-        println!(
-            "Stream ContentType/Size {:?}/{:?}",
-            stream.ContentType(),
-            size
-        );
+        let size: u32 = stream
+            .Size()?
+            .try_into()
+            .map_err(|_| MediaControllerError::default())?;
+        let buffer = Buffer::Create(size)?;
 
         let read_buffer = stream
-            .ReadAsync(&buffer, size, InputStreamOptions::None)
-            .unwrap()
-            .await
-            .unwrap();
+            .ReadAsync(&buffer, size, InputStreamOptions::None)?
+            .await?;
 
-        let data_reader = DataReader::FromBuffer(&read_buffer).unwrap();
+        let data_reader = DataReader::FromBuffer(&read_buffer)?;
         let mut bytes = vec![0u8; size as usize];
-        data_reader.ReadBytes(&mut bytes).unwrap();
+        data_reader.ReadBytes(&mut bytes)?;
 
-        data_reader.Close().unwrap();
-        stream.Close().unwrap();
+        data_reader.Close()?;
+        stream.Close()?;
 
         Ok(bytes)
     }
@@ -237,13 +502,8 @@ impl MediaController {
     }
 
     async fn _media_get_artist(&self) -> Result<String, MediaControllerError> {
-        let session_manager =
-            Media::Control::GlobalSystemMediaTransportControlsSessionManager::RequestAsync()
-                .unwrap()
-                .await
-                .unwrap();
-        let session = session_manager.GetCurrentSession().unwrap();
-        let sesiion_media_properties = session.TryGetMediaPropertiesAsync().unwrap().await.unwrap();
+        let session = self.session()?;
+        let sesiion_media_properties = session.TryGetMediaPropertiesAsync()?.await?;
 
         if let Ok(artist) = sesiion_media_properties.Artist() {
             Ok(format!("{}", artist))
@@ -252,3 +512,95 @@ impl MediaController {
         }
     }
 }
+
+/// The MPRIS metadata keys GSMTC has an equivalent for, leaving out empty ones.
+fn metadata(
+    properties: &GlobalSystemMediaTransportControlsSessionMediaProperties,
+    title: &str,
+    artist: &str,
+    album: &str,
+    duration: f64,
+) -> serde_json::Map<String, serde_json::Value> {
+    let album_artist = properties
+        .AlbumArtist()
+        .map(|artist| format!("{}", artist))
+        .unwrap_or_default();
+    let mut metadata = serde_json::Map::new();
+    if !title.is_empty() {
+        metadata.insert("xesam:title".to_string(), title.into());
+    }
+    if !artist.is_empty() {
+        metadata.insert("xesam:artist".to_string(), vec![artist].into());
+    }
+    if !album.is_empty() {
+        metadata.insert("xesam:album".to_string(), album.into());
+    }
+    if !album_artist.is_empty() {
+        metadata.insert("xesam:albumArtist".to_string(), vec![album_artist].into());
+    }
+    if let Ok(number) = properties.TrackNumber() {
+        if number > 0 {
+            metadata.insert("xesam:trackNumber".to_string(), number.into());
+        }
+    }
+    if duration > 0.0 {
+        // MPRIS lengths are in microseconds.
+        metadata.insert("mpris:length".to_string(), ((duration * 1e6) as i64).into());
+    }
+    metadata
+}
+
+/// GSMTC has no volume, tracklist or rate range, those are always reported as absent.
+fn capabilities(
+    session: &GlobalSystemMediaTransportControlsSession,
+) -> Result<Capabilities, MediaControllerError> {
+    let controls = session.GetPlaybackInfo()?.Controls()?;
+    let can_play = controls.IsPlayEnabled().unwrap_or(false);
+    let can_pause = controls.IsPauseEnabled().unwrap_or(false);
+    let can_go_next = controls.IsNextEnabled().unwrap_or(false);
+    let can_go_previous = controls.IsPreviousEnabled().unwrap_or(false);
+
+    Ok(Capabilities {
+        can_play,
+        can_pause,
+        can_seek: controls.IsPlaybackPositionEnabled().unwrap_or(false),
+        can_go_next,
+        can_go_previous,
+        can_control: can_play || can_pause || can_go_next || can_go_previous,
+        has_volume: false,
+        has_shuffle: controls.IsShuffleEnabled().unwrap_or(false),
+        has_track_list: false,
+        min_rate: 1.0,
+        max_rate: 1.0,
+    })
+}
+
+/// Extrapolates the timeline position to `now`, GSMTC only refreshes it every few seconds.
+fn estimate_position(
+    timeline_properties: &GlobalSystemMediaTransportControlsSessionTimelineProperties,
+    is_playing: bool,
+    rate: f64,
+    now: SystemTime,
+) -> windows::core::Result<f64> {
+    let mut position = timeline_properties.Position()?.Duration as f64 / 10_000_000.0;
+    if is_playing {
+        let now_ticks = unix_millis(now) as i64 * 10_000 + UNIX_EPOCH_TICKS;
+        let updated_ticks = timeline_properties.LastUpdatedTime()?.UniversalTime;
+        position += (now_ticks - updated_ticks).max(0) as f64 / 10_000_000.0 * rate;
+    }
+
+    let end_time = timeline_properties.EndTime()?.Duration as f64 / 10_000_000.0;
+    if end_time > 0.0 {
+        position = position.min(end_time);
+    }
+
+    Ok(position)
+}
+
+impl Drop for MediaController {
+    fn drop(&mut self) {
+        let _ = self
+            .session_manager
+            .RemoveCurrentSessionChanged(self.current_session_changed_token);
+    }
+}
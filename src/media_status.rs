@@ -0,0 +1,97 @@
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Snapshot of the current player, as served by `/status`.
+///
+/// `position` was valid at `timestamp`, so while `is_playing` a client can animate progress
+/// locally as `position + (now - timestamp) * rate` instead of polling `/position`.
+#[derive(Debug, Clone, Serialize)]
+pub struct MediaStatus {
+    /// Name of the player the status was read from.
+    pub player: String,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    /// Track duration in seconds.
+    pub duration: f32,
+    /// Playback position in seconds.
+    pub position: f32,
+    pub rate: f64,
+    pub is_playing: bool,
+    /// Server time, in milliseconds since the Unix epoch, at which `position` was sampled.
+    pub timestamp: u64,
+    pub capabilities: Capabilities,
+    /// The track's metadata under its MPRIS keys, e.g. `xesam:title` or `mpris:artUrl`.
+    pub metadata: serde_json::Map<String, serde_json::Value>,
+}
+
+/// What the current player supports, so clients can hide controls that would fail.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Capabilities {
+    pub can_play: bool,
+    pub can_pause: bool,
+    pub can_seek: bool,
+    pub can_go_next: bool,
+    pub can_go_previous: bool,
+    pub can_control: bool,
+    pub has_volume: bool,
+    pub has_shuffle: bool,
+    pub has_track_list: bool,
+    pub min_rate: f64,
+    pub max_rate: f64,
+}
+
+pub fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// A player the server can control, as listed by `/players`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Player {
+    /// Short name, the MPRIS bus name without its prefix on Linux, e.g. `spotify`, and the
+    /// app id on Windows.
+    pub name: String,
+    /// Name the player gives itself, e.g. `Spotify`.
+    pub identity: String,
+    pub is_playing: bool,
+    /// Whether this is the player the other endpoints control.
+    pub is_active: bool,
+}
+
+/// Entry of the player's `org.mpris.MediaPlayer2.TrackList`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Track {
+    /// MPRIS track id, used to address the track in `GoTo` and `RemoveTrack`.
+    pub id: String,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    /// Track duration in seconds.
+    pub duration: f32,
+    pub url: String,
+    pub is_current: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrackList {
+    pub can_edit_tracks: bool,
+    pub tracks: Vec<Track>,
+}
+
+/// Entry of the player's `org.mpris.MediaPlayer2.Playlists`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Playlist {
+    pub id: String,
+    pub name: String,
+    pub icon: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Playlists {
+    /// Orderings the player accepts in `/playlists?order=`.
+    pub orderings: Vec<String>,
+    pub active: Option<Playlist>,
+    pub playlists: Vec<Playlist>,
+}
@@ -0,0 +1,87 @@
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use std::path::Path;
+use url::Url;
+
+/// Environment variable with the comma separated URI schemes `/open` accepts.
+pub const OPEN_SCHEMES_ENV: &str = "OSMEDIAMOTE_OPEN_SCHEMES";
+const DEFAULT_SCHEMES: &[&str] = &["http", "https"];
+
+#[derive(Debug)]
+pub enum OpenUriError {
+    Invalid(String),
+    SchemeNotAllowed(String),
+}
+
+impl std::fmt::Display for OpenUriError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OpenUriError::Invalid(e) => write!(f, "Invalid URI: {e}"),
+            OpenUriError::SchemeNotAllowed(scheme) => {
+                write!(f, "Scheme {scheme} is not allowed, see {OPEN_SCHEMES_ENV}")
+            }
+        }
+    }
+}
+
+impl ResponseError for OpenUriError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            OpenUriError::Invalid(_) => StatusCode::BAD_REQUEST,
+            OpenUriError::SchemeNotAllowed(_) => StatusCode::FORBIDDEN,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type("text/plain; charset=utf-8")
+            .body(self.to_string())
+    }
+}
+
+/// URI schemes clients may ask the player to open. Local files are only reachable when `file`
+/// is added to the list.
+#[derive(Debug, Clone)]
+pub struct SchemeAllowlist(Vec<String>);
+
+impl SchemeAllowlist {
+    pub fn from_env() -> SchemeAllowlist {
+        let schemes = match std::env::var(OPEN_SCHEMES_ENV) {
+            Ok(schemes) => schemes
+                .split(',')
+                .map(|s| s.trim().to_ascii_lowercase())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            Err(_) => DEFAULT_SCHEMES.iter().map(|s| s.to_string()).collect(),
+        };
+        SchemeAllowlist(schemes)
+    }
+
+    /// Turns a URI or an absolute local path into a URI the player can open.
+    pub fn to_uri(&self, input: &str) -> Result<Url, OpenUriError> {
+        let input = input.trim();
+        let url = if Path::new(input).is_absolute() {
+            Url::from_file_path(input)
+                .map_err(|_| OpenUriError::Invalid(format!("{input} is not a valid path")))?
+        } else {
+            Url::parse(input).map_err(|e| OpenUriError::Invalid(e.to_string()))?
+        };
+
+        if !self.0.iter().any(|s| s == url.scheme()) {
+            return Err(OpenUriError::SchemeNotAllowed(url.scheme().to_string()));
+        }
+
+        if url.scheme() == "file" {
+            let path = url
+                .to_file_path()
+                .map_err(|_| OpenUriError::Invalid(format!("{url} is not a local file")))?;
+            if !path.is_file() {
+                return Err(OpenUriError::Invalid(format!(
+                    "{} does not exist",
+                    path.display()
+                )));
+            }
+        }
+
+        Ok(url)
+    }
+}
@@ -0,0 +1,176 @@
+use crate::media_backend::MediaBackend;
+use crate::media_status::MediaStatus;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Events a slow subscriber can fall behind by before it starts missing them.
+const EVENT_CAPACITY: usize = 64;
+
+/// A track as observed by the monitor.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayedTrack {
+    pub player: String,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    /// Track duration in seconds.
+    pub duration: f32,
+    pub started_at: DateTime<Local>,
+    /// Seconds the track was actually playing, not counting pauses.
+    pub played: f32,
+}
+
+impl PlayedTrack {
+    fn new(status: &MediaStatus, started_at: DateTime<Local>) -> PlayedTrack {
+        PlayedTrack {
+            player: status.player.clone(),
+            title: status.title.clone(),
+            artist: status.artist.clone(),
+            album: status.album.clone(),
+            duration: status.duration,
+            started_at,
+            played: 0.0,
+        }
+    }
+
+    fn is_same_track(&self, status: &MediaStatus) -> bool {
+        self.player == status.player && self.title == status.title && self.artist == status.artist
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum PlaybackEvent {
+    /// A player started exposing media controls.
+    PlayerAppeared { player: String },
+    /// The player went away or another one became the current player.
+    PlayerGone { player: String },
+    /// A new track is loaded in the player.
+    TrackStarted(PlayedTrack),
+    /// The track was replaced or the player went away, `played` is final.
+    TrackEnded {
+        track: PlayedTrack,
+        ended_at: DateTime<Local>,
+    },
+    /// Playback started or stopped, including when a player appears or goes away.
+    PlayingChanged { is_playing: bool },
+}
+
+struct CurrentTrack {
+    track: PlayedTrack,
+    position: f32,
+}
+
+/// Polls the current player and broadcasts track changes, for the history log and other
+/// subsystems reacting to what is being played.
+#[derive(Clone)]
+pub struct PlaybackMonitor {
+    sender: broadcast::Sender<PlaybackEvent>,
+}
+
+impl PlaybackMonitor {
+    /// Starts polling, must be called on the actix runtime.
+    pub fn start(backend: MediaBackend) -> PlaybackMonitor {
+        let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+        let monitor = PlaybackMonitor { sender };
+
+        actix_web::rt::spawn(monitor.clone().run(backend));
+
+        monitor
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<PlaybackEvent> {
+        self.sender.subscribe()
+    }
+
+    async fn run(self, backend: MediaBackend) {
+        let mut player: Option<String> = None;
+        let mut current: Option<CurrentTrack> = None;
+        let mut is_playing = false;
+        let mut sampled_at = Instant::now();
+
+        loop {
+            actix_web::rt::time::sleep(POLL_INTERVAL).await;
+
+            // Errors mean no player is running.
+            let status = backend.call(|mc| mc.media_get_status()).await.ok();
+            let now = Instant::now();
+
+            if let Some(playing) = current.as_mut().filter(|_| is_playing) {
+                playing.track.played += now.duration_since(sampled_at).as_secs_f32();
+            }
+            sampled_at = now;
+
+            let track_status = status.as_ref().filter(|status| !status.title.is_empty());
+            let track_changed = match (&current, track_status) {
+                (Some(playing), Some(status)) => {
+                    !playing.track.is_same_track(status) || restarted(playing, status)
+                }
+                (None, None) => false,
+                _ => true,
+            };
+
+            if track_changed {
+                if let Some(ended) = current.take() {
+                    self.send(PlaybackEvent::TrackEnded {
+                        track: ended.track,
+                        ended_at: Local::now(),
+                    });
+                }
+            }
+
+            let new_player = status.as_ref().map(|status| status.player.clone());
+            if new_player != player {
+                if let Some(player) = player.take() {
+                    self.send(PlaybackEvent::PlayerGone { player });
+                }
+                if let Some(player) = &new_player {
+                    self.send(PlaybackEvent::PlayerAppeared {
+                        player: player.clone(),
+                    });
+                }
+                player = new_player;
+            }
+
+            if let Some(status) = track_status {
+                match current.as_mut() {
+                    Some(playing) => {
+                        playing.position = status.position;
+                        // Some players only learn the duration after the track started.
+                        if playing.track.duration <= 0.0 {
+                            playing.track.duration = status.duration;
+                        }
+                    }
+                    None => {
+                        let track = PlayedTrack::new(status, Local::now());
+                        self.send(PlaybackEvent::TrackStarted(track.clone()));
+                        current = Some(CurrentTrack {
+                            track,
+                            position: status.position,
+                        });
+                    }
+                }
+            }
+
+            let now_playing = status.as_ref().is_some_and(|status| status.is_playing);
+            if now_playing != is_playing {
+                is_playing = now_playing;
+                self.send(PlaybackEvent::PlayingChanged { is_playing });
+            }
+        }
+    }
+
+    fn send(&self, event: PlaybackEvent) {
+        // Fails only while nobody is subscribed.
+        let _ = self.sender.send(event);
+    }
+}
+
+/// Whether the same track started over, e.g. when repeating a single track.
+fn restarted(playing: &CurrentTrack, status: &MediaStatus) -> bool {
+    playing.track.duration > 0.0
+        && playing.position >= playing.track.duration * 0.9
+        && status.position < playing.track.duration * 0.1
+}
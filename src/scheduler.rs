@@ -0,0 +1,603 @@
+use crate::media_backend::{MediaBackend, MediaBackendError};
+use crate::storage;
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use chrono::{
+    DateTime, Datelike, Days, Local, LocalResult, NaiveDateTime, NaiveTime, TimeDelta, TimeZone,
+    Weekday,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const SCHEDULES_FILE: &str = "schedules.json";
+const TICK: Duration = Duration::from_secs(1);
+/// Runs missed by more than this, e.g. while the machine was asleep, are skipped instead of
+/// fired late.
+const MISSED_GRACE: TimeDelta = TimeDelta::minutes(5);
+/// How far back runs missed while the server was down are looked for on startup.
+const CATCH_UP: TimeDelta = TimeDelta::hours(1);
+const LOG_CAPACITY: usize = 200;
+
+#[derive(Debug)]
+pub enum SchedulerError {
+    Invalid(String),
+    NotFound(u64),
+    Storage(std::io::Error),
+}
+
+impl std::fmt::Display for SchedulerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchedulerError::Invalid(e) => write!(f, "{e}"),
+            SchedulerError::NotFound(id) => write!(f, "No schedule with id {id}"),
+            SchedulerError::Storage(e) => write!(f, "Could not save schedules: {e}"),
+        }
+    }
+}
+
+impl ResponseError for SchedulerError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SchedulerError::Invalid(_) => StatusCode::BAD_REQUEST,
+            SchedulerError::NotFound(_) => StatusCode::NOT_FOUND,
+            SchedulerError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type("text/plain; charset=utf-8")
+            .body(self.to_string())
+    }
+}
+
+impl From<std::io::Error> for SchedulerError {
+    fn from(e: std::io::Error) -> Self {
+        SchedulerError::Storage(e)
+    }
+}
+
+/// When a schedule fires, in the server's local time.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum When {
+    /// Fires once, the schedule is disabled afterwards.
+    Once { at: NaiveDateTime },
+    /// Fires every day at `time`, or only on `days` when it is not empty.
+    Daily {
+        time: NaiveTime,
+        #[serde(default)]
+        days: Vec<Weekday>,
+    },
+}
+
+impl When {
+    /// First time strictly after `after` this fires.
+    fn next_after<Tz: TimeZone>(&self, after: DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let timezone = after.timezone();
+        match self {
+            When::Once { at } => resolve(*at, &timezone).filter(|at| *at > after),
+            When::Daily { time, days } => (0..=7)
+                .filter_map(|offset| after.date_naive().checked_add_days(Days::new(offset)))
+                .filter(|date| days.is_empty() || days.contains(&date.weekday()))
+                .filter_map(|date| resolve(date.and_time(*time), &timezone))
+                .find(|at| *at > after),
+        }
+    }
+}
+
+/// The instant `local` stands for. A time repeated when the clocks go back is the first one,
+/// and a time skipped when they go forward is an hour later on the clock, right after the
+/// change.
+fn resolve<Tz: TimeZone>(local: NaiveDateTime, timezone: &Tz) -> Option<DateTime<Tz>> {
+    match timezone.from_local_datetime(&local) {
+        LocalResult::Single(at) | LocalResult::Ambiguous(at, _) => Some(at),
+        LocalResult::None => timezone
+            .from_local_datetime(&(local + TimeDelta::hours(1)))
+            .earliest(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Action {
+    Play,
+    Pause,
+    PlayPause,
+    Stop,
+    Next,
+    Previous,
+    /// Sets the volume, where 1.0 is the player's full volume.
+    SetVolume {
+        volume: f64,
+    },
+}
+
+impl Action {
+    async fn run(&self, backend: &MediaBackend) -> Result<(), MediaBackendError> {
+        match *self {
+            Action::Play => backend.call(|mc| mc.media_play()).await,
+            Action::Pause => backend.call(|mc| mc.media_pause()).await,
+            Action::PlayPause => backend.call(|mc| mc.media_play_pause()).await,
+            Action::Stop => backend.call(|mc| mc.media_stop()).await,
+            Action::Next => backend.call(|mc| mc.media_play_next()).await,
+            Action::Previous => backend.call(|mc| mc.media_play_prev()).await,
+            Action::SetVolume { volume } => {
+                backend.call(move |mc| mc.media_set_volume(volume)).await
+            }
+        }
+    }
+}
+
+/// Body of `POST /schedules` and `PUT /schedules/{id}`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScheduleRequest {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    pub when: When,
+    pub action: Action,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Schedule {
+    pub id: u64,
+    pub name: String,
+    pub enabled: bool,
+    pub when: When,
+    pub action: Action,
+}
+
+/// A schedule as listed by the API, with the time it fires next.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduleView {
+    #[serde(flatten)]
+    pub schedule: Schedule,
+    pub next_run: Option<DateTime<Local>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Ok,
+    Failed,
+    /// The run was missed by more than the grace period and not executed.
+    Skipped,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LogEntry {
+    pub schedule_id: u64,
+    pub name: String,
+    pub action: Action,
+    pub scheduled_for: DateTime<Local>,
+    pub executed_at: DateTime<Local>,
+    pub outcome: Outcome,
+    pub error: Option<String>,
+}
+
+struct SchedulerState {
+    schedules: Vec<Schedule>,
+    next_id: u64,
+    /// Everything up to this instant has been checked for due runs.
+    checked_until: DateTime<Local>,
+    log: VecDeque<LogEntry>,
+}
+
+impl SchedulerState {
+    fn push_log(&mut self, entry: LogEntry) {
+        if self.log.len() == LOG_CAPACITY {
+            self.log.pop_front();
+        }
+        self.log.push_back(entry);
+    }
+
+    /// Saves `schedules` and only then makes them the current ones, so a failed save changes
+    /// nothing.
+    fn replace(&mut self, schedules: Vec<Schedule>) -> std::io::Result<()> {
+        storage::save_json(SCHEDULES_FILE, &schedules)?;
+        self.schedules = schedules;
+        Ok(())
+    }
+}
+
+/// Runs media commands at set times, e.g. play at 07:00 on weekdays or pause at 23:00.
+///
+/// Schedules are kept in `schedules.json` in the data dir, see [`storage::data_dir`], and
+/// checked every second. The last runs are kept in an in-memory log.
+#[derive(Clone)]
+pub struct Scheduler {
+    backend: MediaBackend,
+    state: Arc<Mutex<SchedulerState>>,
+}
+
+impl Scheduler {
+    /// Loads the saved schedules and starts checking them, must be called on the actix runtime.
+    pub fn start(backend: MediaBackend) -> std::io::Result<Scheduler> {
+        let schedules: Vec<Schedule> = storage::load_json(SCHEDULES_FILE)?;
+        let next_id = schedules.iter().map(|s| s.id + 1).max().unwrap_or(0);
+        let scheduler = Scheduler {
+            backend,
+            state: Arc::new(Mutex::new(SchedulerState {
+                schedules,
+                next_id,
+                checked_until: Local::now() - CATCH_UP,
+                log: VecDeque::new(),
+            })),
+        };
+
+        actix_web::rt::spawn(scheduler.clone().run());
+
+        Ok(scheduler)
+    }
+
+    pub fn list(&self) -> Vec<ScheduleView> {
+        let now = Local::now();
+        self.state
+            .lock()
+            .unwrap()
+            .schedules
+            .iter()
+            .map(|schedule| view(schedule, now))
+            .collect()
+    }
+
+    pub fn get(&self, id: u64) -> Result<ScheduleView, SchedulerError> {
+        let state = self.state.lock().unwrap();
+        state
+            .schedules
+            .iter()
+            .find(|schedule| schedule.id == id)
+            .map(|schedule| view(schedule, Local::now()))
+            .ok_or(SchedulerError::NotFound(id))
+    }
+
+    pub fn add(&self, request: ScheduleRequest) -> Result<ScheduleView, SchedulerError> {
+        validate(&request)?;
+
+        let mut state = self.state.lock().unwrap();
+        let schedule = Schedule {
+            id: state.next_id,
+            name: request.name,
+            enabled: request.enabled,
+            when: request.when,
+            action: request.action,
+        };
+        let mut schedules = state.schedules.clone();
+        schedules.push(schedule.clone());
+        state.replace(schedules)?;
+        state.next_id += 1;
+
+        Ok(view(&schedule, Local::now()))
+    }
+
+    /// Replaces schedule `id`.
+    pub fn update(
+        &self,
+        id: u64,
+        request: ScheduleRequest,
+    ) -> Result<ScheduleView, SchedulerError> {
+        validate(&request)?;
+
+        let mut state = self.state.lock().unwrap();
+        let mut schedules = state.schedules.clone();
+        let schedule = schedules
+            .iter_mut()
+            .find(|schedule| schedule.id == id)
+            .ok_or(SchedulerError::NotFound(id))?;
+        *schedule = Schedule {
+            id,
+            name: request.name,
+            enabled: request.enabled,
+            when: request.when,
+            action: request.action,
+        };
+        let schedule = schedule.clone();
+        state.replace(schedules)?;
+
+        Ok(view(&schedule, Local::now()))
+    }
+
+    pub fn remove(&self, id: u64) -> Result<(), SchedulerError> {
+        let mut state = self.state.lock().unwrap();
+        let mut schedules = state.schedules.clone();
+        schedules.retain(|schedule| schedule.id != id);
+        if schedules.len() == state.schedules.len() {
+            return Err(SchedulerError::NotFound(id));
+        }
+        state.replace(schedules)?;
+
+        Ok(())
+    }
+
+    /// Execution log, oldest first.
+    pub fn log(&self) -> Vec<LogEntry> {
+        self.state.lock().unwrap().log.iter().cloned().collect()
+    }
+
+    async fn run(self) {
+        loop {
+            actix_web::rt::time::sleep(TICK).await;
+
+            let now = Local::now();
+            let due = self.take_due(now);
+
+            for (schedule, scheduled_for) in due {
+                let (outcome, error) = if now - scheduled_for > MISSED_GRACE {
+                    log::warn!(
+                        "Skipping schedule {} missed at {scheduled_for}",
+                        schedule.id
+                    );
+                    (Outcome::Skipped, None)
+                } else {
+                    match schedule.action.run(&self.backend).await {
+                        Ok(()) => (Outcome::Ok, None),
+                        Err(e) => {
+                            log::warn!("Schedule {} failed: {e}", schedule.id);
+                            (Outcome::Failed, Some(e.to_string()))
+                        }
+                    }
+                };
+
+                self.state.lock().unwrap().push_log(LogEntry {
+                    schedule_id: schedule.id,
+                    name: schedule.name,
+                    action: schedule.action,
+                    scheduled_for,
+                    executed_at: Local::now(),
+                    outcome,
+                    error,
+                });
+            }
+        }
+    }
+
+    /// Collects the runs due since the last check, disabling one-shot schedules that fire.
+    ///
+    /// One-shot schedules only run once they are saved as disabled, otherwise they would run
+    /// again after a restart. When the save fails they are logged as failed and stay enabled.
+    fn take_due(&self, now: DateTime<Local>) -> Vec<(Schedule, DateTime<Local>)> {
+        let mut state = self.state.lock().unwrap();
+        let checked_until = std::mem::replace(&mut state.checked_until, now);
+
+        let mut due = Vec::new();
+        let mut schedules = state.schedules.clone();
+        let mut changed = false;
+        for schedule in schedules.iter_mut().filter(|s| s.enabled) {
+            let Some(scheduled_for) = schedule.when.next_after(checked_until) else {
+                continue;
+            };
+            if scheduled_for > now {
+                continue;
+            }
+            if let When::Once { .. } = schedule.when {
+                schedule.enabled = false;
+                changed = true;
+            }
+            due.push((schedule.clone(), scheduled_for));
+        }
+
+        if changed {
+            if let Err(e) = state.replace(schedules) {
+                log::warn!("Could not save schedules, not running one-shot schedules: {e}");
+                let (once, daily) = due
+                    .into_iter()
+                    .partition(|(schedule, _)| matches!(schedule.when, When::Once { .. }));
+                due = daily;
+                for (schedule, scheduled_for) in once {
+                    state.push_log(LogEntry {
+                        schedule_id: schedule.id,
+                        name: schedule.name,
+                        action: schedule.action,
+                        scheduled_for,
+                        executed_at: now,
+                        outcome: Outcome::Failed,
+                        error: Some(format!("Could not save schedules: {e}")),
+                    });
+                }
+            }
+        }
+
+        due
+    }
+}
+
+fn view(schedule: &Schedule, now: DateTime<Local>) -> ScheduleView {
+    ScheduleView {
+        next_run: schedule
+            .enabled
+            .then(|| schedule.when.next_after(now))
+            .flatten(),
+        schedule: schedule.clone(),
+    }
+}
+
+fn validate(request: &ScheduleRequest) -> Result<(), SchedulerError> {
+    if let Action::SetVolume { volume } = request.action {
+        if !(0.0..=1.0).contains(&volume) {
+            return Err(SchedulerError::Invalid(
+                "Volume must be between 0 and 1".to_string(),
+            ));
+        }
+    }
+    if request.enabled && request.when.next_after(Local::now()).is_none() {
+        return Err(SchedulerError::Invalid(
+            "Schedule would never run, `at` is in the past".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use chrono_tz::Europe::Berlin;
+
+    fn local(date: &str, time: &str) -> NaiveDateTime {
+        NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .unwrap()
+            .and_time(NaiveTime::parse_from_str(time, "%H:%M").unwrap())
+    }
+
+    fn berlin(date: &str, time: &str) -> DateTime<chrono_tz::Tz> {
+        Berlin.from_local_datetime(&local(date, time)).unwrap()
+    }
+
+    fn daily(time: &str, days: &[Weekday]) -> When {
+        When::Daily {
+            time: NaiveTime::parse_from_str(time, "%H:%M").unwrap(),
+            days: days.to_vec(),
+        }
+    }
+
+    #[test]
+    fn daily_fires_later_today_or_tomorrow() {
+        let when = daily("07:00", &[]);
+
+        assert_eq!(
+            when.next_after(berlin("2025-01-06", "06:59")),
+            Some(berlin("2025-01-06", "07:00"))
+        );
+        // Strictly after, a run at 07:00 is not due again at 07:00.
+        assert_eq!(
+            when.next_after(berlin("2025-01-06", "07:00")),
+            Some(berlin("2025-01-07", "07:00"))
+        );
+    }
+
+    #[test]
+    fn daily_skips_to_the_next_listed_weekday() {
+        let when = daily("07:00", &[Weekday::Mon, Weekday::Fri]);
+
+        // 2025-01-07 is a Tuesday.
+        assert_eq!(
+            when.next_after(berlin("2025-01-07", "12:00")),
+            Some(berlin("2025-01-10", "07:00"))
+        );
+        // From Friday after the run, over the weekend.
+        assert_eq!(
+            when.next_after(berlin("2025-01-10", "08:00")),
+            Some(berlin("2025-01-13", "07:00"))
+        );
+    }
+
+    #[test]
+    fn daily_on_one_weekday_waits_a_week() {
+        let when = daily("07:00", &[Weekday::Mon]);
+
+        assert_eq!(
+            when.next_after(berlin("2025-01-06", "07:00")),
+            Some(berlin("2025-01-13", "07:00"))
+        );
+    }
+
+    #[test]
+    fn time_skipped_by_daylight_saving_fires_after_the_change() {
+        // Clocks went from 02:00 to 03:00 on 2025-03-30 in Berlin.
+        let when = daily("02:30", &[]);
+
+        let next = when.next_after(berlin("2025-03-30", "01:00")).unwrap();
+        assert_eq!(next, berlin("2025-03-30", "03:30"));
+        assert_eq!(when.next_after(next), Some(berlin("2025-03-31", "02:30")));
+    }
+
+    #[test]
+    fn time_repeated_by_daylight_saving_fires_once() {
+        // Clocks went from 03:00 back to 02:00 on 2025-10-26 in Berlin.
+        let when = daily("02:30", &[]);
+
+        let first = when.next_after(berlin("2025-10-26", "01:00")).unwrap();
+        assert_eq!(
+            Some(first),
+            Berlin
+                .from_local_datetime(&local("2025-10-26", "02:30"))
+                .earliest()
+        );
+        assert_eq!(when.next_after(first), Some(berlin("2025-10-27", "02:30")));
+    }
+
+    #[test]
+    fn once_fires_only_if_still_ahead() {
+        let when = When::Once {
+            at: local("2025-01-06", "07:00"),
+        };
+
+        assert_eq!(
+            when.next_after(berlin("2025-01-06", "06:00")),
+            Some(berlin("2025-01-06", "07:00"))
+        );
+        assert_eq!(when.next_after(berlin("2025-01-06", "07:00")), None);
+    }
+
+    fn scheduler(schedules: &[Schedule], checked_until: DateTime<Local>) -> Scheduler {
+        storage::use_temp_data_dir();
+        Scheduler {
+            backend: MediaBackend::spawn().unwrap(),
+            state: Arc::new(Mutex::new(SchedulerState {
+                schedules: schedules.to_vec(),
+                next_id: schedules.len() as u64,
+                checked_until,
+                log: VecDeque::new(),
+            })),
+        }
+    }
+
+    #[test]
+    fn one_shot_schedules_only_run_once_saved_as_disabled() {
+        let now = Local::now();
+        let minute_ago = now - TimeDelta::minutes(1);
+        let schedules = [
+            Schedule {
+                id: 0,
+                name: "once".to_string(),
+                enabled: true,
+                when: When::Once {
+                    at: minute_ago.naive_local(),
+                },
+                action: Action::Pause,
+            },
+            Schedule {
+                id: 1,
+                name: "daily".to_string(),
+                enabled: true,
+                when: daily(&minute_ago.format("%H:%M").to_string(), &[]),
+                action: Action::Play,
+            },
+        ];
+        let due_ids = |due: Vec<(Schedule, DateTime<Local>)>| {
+            due.into_iter().map(|(s, _)| s.id).collect::<Vec<_>>()
+        };
+
+        // A directory in the way of the file makes the save fail.
+        storage::use_temp_data_dir();
+        let path = storage::data_file(SCHEDULES_FILE);
+        std::fs::create_dir_all(&path).unwrap();
+        let failing = scheduler(&schedules, now - TimeDelta::minutes(2));
+        assert_eq!(due_ids(failing.take_due(now)), [1]);
+        {
+            let state = failing.state.lock().unwrap();
+            assert!(state.schedules[0].enabled);
+            let [entry] = state.log.iter().collect::<Vec<_>>()[..] else {
+                panic!("expected one log entry, got {:?}", state.log);
+            };
+            assert_eq!(entry.schedule_id, 0);
+            assert_eq!(entry.outcome, Outcome::Failed);
+        }
+
+        std::fs::remove_dir(&path).unwrap();
+        let saving = scheduler(&schedules, now - TimeDelta::minutes(2));
+        assert_eq!(due_ids(saving.take_due(now)), [0, 1]);
+        assert!(!saving.state.lock().unwrap().schedules[0].enabled);
+        let saved: Vec<Schedule> = storage::load_json(SCHEDULES_FILE).unwrap();
+        assert!(!saved[0].enabled);
+        assert!(saved[1].enabled);
+    }
+}
@@ -0,0 +1,573 @@
+use crate::playback_monitor::{PlaybackEvent, PlaybackMonitor, PlayedTrack};
+use crate::storage;
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Notify;
+
+/// Environment variable holding the user token, scrobbling is off until it is set.
+pub const LISTENBRAINZ_TOKEN_ENV: &str = "OSMEDIAMOTE_LISTENBRAINZ_TOKEN";
+/// Environment variable overriding the API root, for self-hosted ListenBrainz-compatible servers.
+pub const LISTENBRAINZ_URL_ENV: &str = "OSMEDIAMOTE_LISTENBRAINZ_URL";
+const DEFAULT_URL: &str = "https://api.listenbrainz.org";
+
+const QUEUE_FILE: &str = "scrobble_queue.json";
+/// Most listens sent in one request.
+const BATCH_SIZE: usize = 100;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_RETRY: Duration = Duration::from_secs(30);
+const MAX_RETRY: Duration = Duration::from_secs(30 * 60);
+
+/// Tracks shorter than this are never scrobbled.
+const MIN_TRACK_LENGTH: f32 = 30.0;
+/// A track is scrobbled once played for half its length or this long, whichever comes first.
+const MAX_PLAYED_REQUIRED: f32 = 4.0 * 60.0;
+
+#[derive(Debug, Clone)]
+pub struct ScrobblerConfig {
+    pub url: String,
+    pub token: String,
+}
+
+impl ScrobblerConfig {
+    /// Reads the configuration, `None` when no token is set.
+    pub fn from_env() -> Option<ScrobblerConfig> {
+        let token = std::env::var(LISTENBRAINZ_TOKEN_ENV)
+            .ok()
+            .filter(|t| !t.is_empty())?;
+        let url = std::env::var(LISTENBRAINZ_URL_ENV)
+            .ok()
+            .filter(|u| !u.is_empty())
+            .unwrap_or_else(|| DEFAULT_URL.to_string());
+
+        Some(ScrobblerConfig {
+            url: url.trim_end_matches('/').to_string(),
+            token,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackMetadata {
+    pub artist_name: String,
+    pub track_name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub release_name: Option<String>,
+    pub additional_info: AdditionalInfo,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdditionalInfo {
+    pub duration_ms: u64,
+    pub media_player: String,
+    pub submission_client: String,
+}
+
+/// A listen in the ListenBrainz submission format.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Listen {
+    /// Unix seconds at which the track started, absent for "playing now".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listened_at: Option<i64>,
+    pub track_metadata: TrackMetadata,
+}
+
+impl Listen {
+    fn new(track: &PlayedTrack, listened_at: Option<i64>) -> Listen {
+        Listen {
+            listened_at,
+            track_metadata: TrackMetadata {
+                artist_name: track.artist.clone(),
+                track_name: track.title.clone(),
+                release_name: Some(track.album.clone()).filter(|album| !album.is_empty()),
+                additional_info: AdditionalInfo {
+                    duration_ms: (track.duration.max(0.0) * 1000.0) as u64,
+                    media_player: track.player.clone(),
+                    submission_client: env!("CARGO_PKG_NAME").to_string(),
+                },
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Submission<'a> {
+    listen_type: &'a str,
+    payload: &'a [Listen],
+}
+
+/// Reported by `GET /scrobbler`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScrobblerStatus {
+    pub enabled: bool,
+    /// Listens waiting to be submitted.
+    pub queued: usize,
+    pub last_error: Option<String>,
+}
+
+struct ScrobblerState {
+    queue: Vec<Listen>,
+    last_error: Option<String>,
+}
+
+enum SubmitError {
+    /// The server refused the listens themselves, retrying would not help.
+    Rejected(String),
+    Failed(String),
+}
+
+/// Submits played tracks to a ListenBrainz-compatible server.
+///
+/// Tracks are scrobbled by the usual rules: longer than 30 seconds and played for half their
+/// length or four minutes. Listens that cannot be sent are kept in `scrobble_queue.json` in the
+/// data dir and retried with a growing delay, so nothing is lost while offline.
+#[derive(Clone)]
+pub struct Scrobbler {
+    config: Option<Arc<ScrobblerConfig>>,
+    client: reqwest::Client,
+    state: Arc<Mutex<ScrobblerState>>,
+    queued: Arc<Notify>,
+    /// Held while the queue is written, so an older copy never replaces a newer one.
+    saving: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Scrobbler {
+    /// Starts scrobbling the monitor's tracks when configured, must be called on the actix
+    /// runtime.
+    pub fn start(
+        config: Option<ScrobblerConfig>,
+        monitor: &PlaybackMonitor,
+    ) -> std::io::Result<Scrobbler> {
+        let queue: Vec<Listen> = storage::load_json(QUEUE_FILE)?;
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(std::io::Error::other)?;
+        let scrobbler = Scrobbler {
+            config: config.map(Arc::new),
+            client,
+            state: Arc::new(Mutex::new(ScrobblerState {
+                queue,
+                last_error: None,
+            })),
+            queued: Arc::new(Notify::new()),
+            saving: Arc::new(tokio::sync::Mutex::new(())),
+        };
+
+        if let Some(config) = &scrobbler.config {
+            log::info!("Scrobbling to {}", config.url);
+            actix_web::rt::spawn(scrobbler.clone().listen(monitor.subscribe()));
+            actix_web::rt::spawn(scrobbler.clone().flush());
+        }
+
+        Ok(scrobbler)
+    }
+
+    pub fn status(&self) -> ScrobblerStatus {
+        let state = self.state.lock().unwrap();
+        ScrobblerStatus {
+            enabled: self.config.is_some(),
+            queued: state.queue.len(),
+            last_error: state.last_error.clone(),
+        }
+    }
+
+    async fn listen(self, mut events: tokio::sync::broadcast::Receiver<PlaybackEvent>) {
+        loop {
+            match events.recv().await {
+                Ok(PlaybackEvent::TrackStarted(track)) => {
+                    if track.artist.is_empty() {
+                        continue;
+                    }
+                    let now_playing = [Listen::new(&track, None)];
+                    // Best effort, a missed "playing now" is not worth retrying.
+                    if let Err(SubmitError::Rejected(e) | SubmitError::Failed(e)) =
+                        self.submit("playing_now", &now_playing).await
+                    {
+                        log::debug!("Could not send playing now: {e}");
+                    }
+                }
+                Ok(PlaybackEvent::TrackEnded { track, .. }) => {
+                    if !should_scrobble(&track) {
+                        continue;
+                    }
+                    let listen = Listen::new(&track, Some(track.started_at.timestamp()));
+                    self.state.lock().unwrap().queue.push(listen);
+                    self.queued.notify_one();
+                    self.save_queue().await;
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => {
+                    log::warn!("Scrobbler missed {missed} playback events");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    /// Sends the queued listens, backing off while the server cannot be reached.
+    ///
+    /// A batch the server refuses is split in halves until the listens it refuses are sent on
+    /// their own, so only those are dropped.
+    async fn flush(self) {
+        let mut retry = MIN_RETRY;
+        let mut batch_size = BATCH_SIZE;
+
+        loop {
+            let batch: Vec<Listen> = {
+                let state = self.state.lock().unwrap();
+                state.queue.iter().take(batch_size).cloned().collect()
+            };
+            if batch.is_empty() {
+                self.queued.notified().await;
+                continue;
+            }
+
+            let listen_type = if batch.len() == 1 { "single" } else { "import" };
+            let result = self.submit(listen_type, &batch).await;
+
+            let (sent, failed) = {
+                let mut state = self.state.lock().unwrap();
+                let (sent, failed) = match result {
+                    Ok(()) => {
+                        state.last_error = None;
+                        (true, false)
+                    }
+                    Err(SubmitError::Rejected(e)) if batch.len() > 1 => {
+                        log::debug!("Splitting {} refused listens: {e}", batch.len());
+                        batch_size = batch.len() / 2;
+                        (false, false)
+                    }
+                    Err(SubmitError::Rejected(e)) => {
+                        log::warn!("Dropping a listen refused by the server: {e}");
+                        state.last_error = Some(e);
+                        batch_size = BATCH_SIZE;
+                        (true, false)
+                    }
+                    Err(SubmitError::Failed(e)) => {
+                        log::warn!("Could not submit listens, retrying in {retry:?}: {e}");
+                        state.last_error = Some(e);
+                        (false, true)
+                    }
+                };
+                if sent {
+                    // Listens queued while the request was in flight were appended after the batch.
+                    state.queue.drain(..batch.len());
+                }
+                (sent, failed)
+            };
+
+            if sent {
+                self.save_queue().await;
+            }
+            if failed {
+                let _ = actix_web::rt::time::timeout(retry, self.queued.notified()).await;
+                retry = (retry * 2).min(MAX_RETRY);
+            } else {
+                retry = MIN_RETRY;
+            }
+        }
+    }
+
+    /// Writes the queue to the data dir on the blocking thread pool.
+    async fn save_queue(&self) {
+        let _saving = self.saving.lock().await;
+        let queue = self.state.lock().unwrap().queue.clone();
+        let saved = web::block(move || storage::save_json(QUEUE_FILE, &queue)).await;
+        match saved {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => log::warn!("Could not save the scrobble queue: {e}"),
+            Err(e) => log::warn!("Could not save the scrobble queue: {e}"),
+        }
+    }
+
+    async fn submit(&self, listen_type: &str, payload: &[Listen]) -> Result<(), SubmitError> {
+        let Some(config) = &self.config else {
+            return Ok(());
+        };
+
+        let response = self
+            .client
+            .post(format!("{}/1/submit-listens", config.url))
+            .header("Authorization", format!("Token {}", config.token))
+            .json(&Submission {
+                listen_type,
+                payload,
+            })
+            .send()
+            .await
+            .map_err(|e| SubmitError::Failed(e.to_string()))?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let body = response.text().await.unwrap_or_default();
+        let error = format!("{status}: {body}");
+        if status == reqwest::StatusCode::BAD_REQUEST {
+            Err(SubmitError::Rejected(error))
+        } else {
+            Err(SubmitError::Failed(error))
+        }
+    }
+}
+
+fn should_scrobble(track: &PlayedTrack) -> bool {
+    !track.artist.is_empty()
+        && !track.title.is_empty()
+        && track.duration > MIN_TRACK_LENGTH
+        && track.played >= (track.duration / 2.0).min(MAX_PLAYED_REQUIRED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use chrono::{Local, TimeZone};
+
+    /// A request received by the stub server: its path, `Authorization` header and body.
+    type Received = (String, Option<String>, serde_json::Value);
+
+    /// Starts a ListenBrainz stand-in answering each request with the status `status` gives for
+    /// its body, returning its URL and the requests it received.
+    fn stub(
+        status: impl Fn(&serde_json::Value) -> u16 + Clone + Send + 'static,
+    ) -> (String, Arc<Mutex<Vec<Received>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let log = web::Data::new(received.clone());
+        let server = HttpServer::new(move || {
+            let status = status.clone();
+            App::new().app_data(log.clone()).default_service(web::to(
+                move |req: HttpRequest,
+                      body: web::Json<serde_json::Value>,
+                      log: web::Data<Arc<Mutex<Vec<Received>>>>| {
+                    let status = status(&body);
+                    async move {
+                        let auth = req
+                            .headers()
+                            .get("Authorization")
+                            .and_then(|value| value.to_str().ok())
+                            .map(str::to_string);
+                        log.lock()
+                            .unwrap()
+                            .push((req.path().to_string(), auth, body.into_inner()));
+                        HttpResponse::build(actix_web::http::StatusCode::from_u16(status).unwrap())
+                            .body("stub reply")
+                    }
+                },
+            ))
+        })
+        .workers(1)
+        .disable_signals()
+        .listen(listener)
+        .unwrap()
+        .run();
+        actix_web::rt::spawn(server);
+
+        (url, received)
+    }
+
+    fn scrobbler(url: &str, queue: Vec<Listen>) -> Scrobbler {
+        storage::use_temp_data_dir();
+        Scrobbler {
+            config: Some(Arc::new(ScrobblerConfig {
+                url: url.to_string(),
+                token: "secret".to_string(),
+            })),
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .unwrap(),
+            state: Arc::new(Mutex::new(ScrobblerState {
+                queue,
+                last_error: None,
+            })),
+            queued: Arc::new(Notify::new()),
+            saving: Arc::new(tokio::sync::Mutex::new(())),
+        }
+    }
+
+    fn track(title: &str, duration: f32, played: f32) -> PlayedTrack {
+        PlayedTrack {
+            player: "Fake".to_string(),
+            title: title.to_string(),
+            artist: "Artist".to_string(),
+            album: String::new(),
+            duration,
+            started_at: Local.timestamp_opt(1_700_000_000, 0).unwrap(),
+            played,
+        }
+    }
+
+    fn listen(title: &str) -> Listen {
+        let track = track(title, 200.0, 200.0);
+        Listen::new(&track, Some(track.started_at.timestamp()))
+    }
+
+    /// Waits up to five seconds for `done` to hold.
+    async fn wait_until(mut done: impl FnMut() -> bool) {
+        for _ in 0..100 {
+            if done() {
+                return;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("timed out waiting for the scrobbler");
+    }
+
+    #[test]
+    fn scrobbles_long_tracks_played_long_enough() {
+        assert!(should_scrobble(&track("Song", 200.0, 100.0)));
+        assert!(!should_scrobble(&track("Song", 200.0, 99.0)));
+        // Four minutes are enough however long the track is.
+        assert!(should_scrobble(&track("Song", 3600.0, 240.0)));
+        assert!(!should_scrobble(&track("Song", 30.0, 30.0)));
+        assert!(!should_scrobble(&track("", 200.0, 200.0)));
+    }
+
+    #[actix_web::test]
+    async fn submit_posts_listens_with_the_token() {
+        let (url, received) = stub(|_| 200);
+        let scrobbler = scrobbler(&url, Vec::new());
+
+        assert!(scrobbler.submit("single", &[listen("Song")]).await.is_ok());
+
+        let received = received.lock().unwrap();
+        let [(path, auth, body)] = received.as_slice() else {
+            panic!("expected one request, got {received:?}");
+        };
+        assert_eq!(path, "/1/submit-listens");
+        assert_eq!(auth.as_deref(), Some("Token secret"));
+        assert_eq!(body["listen_type"], "single");
+        let listen = &body["payload"][0];
+        assert_eq!(listen["listened_at"], 1_700_000_000);
+        assert_eq!(listen["track_metadata"]["track_name"], "Song");
+        assert_eq!(
+            listen["track_metadata"]["additional_info"]["duration_ms"],
+            200_000
+        );
+        // An empty album is left out rather than sent as "".
+        assert!(listen["track_metadata"].get("release_name").is_none());
+    }
+
+    #[actix_web::test]
+    async fn submit_tells_refused_listens_from_failures() {
+        let (url, _) = stub(|_| 400);
+        let result = scrobbler(&url, Vec::new())
+            .submit("single", &[listen("Song")])
+            .await;
+        assert!(matches!(result, Err(SubmitError::Rejected(e)) if e.contains("stub reply")));
+
+        let (url, _) = stub(|_| 503);
+        let result = scrobbler(&url, Vec::new())
+            .submit("single", &[listen("Song")])
+            .await;
+        assert!(matches!(result, Err(SubmitError::Failed(_))));
+
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", closed.local_addr().unwrap());
+        drop(closed);
+        let result = scrobbler(&url, Vec::new())
+            .submit("single", &[listen("Song")])
+            .await;
+        assert!(matches!(result, Err(SubmitError::Failed(_))));
+    }
+
+    #[actix_web::test]
+    async fn flush_sends_the_queue_in_one_import() {
+        let (url, received) = stub(|_| 200);
+        let scrobbler = scrobbler(&url, vec![listen("One"), listen("Two"), listen("Three")]);
+        actix_web::rt::spawn(scrobbler.clone().flush());
+
+        wait_until(|| scrobbler.status().queued == 0).await;
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].2["listen_type"], "import");
+        assert_eq!(received[0].2["payload"].as_array().unwrap().len(), 3);
+        assert_eq!(scrobbler.status().last_error, None);
+    }
+
+    #[actix_web::test]
+    async fn flush_drops_refused_listens() {
+        let (url, _) = stub(|_| 400);
+        let scrobbler = scrobbler(&url, vec![listen("Song")]);
+        actix_web::rt::spawn(scrobbler.clone().flush());
+
+        wait_until(|| scrobbler.status().queued == 0).await;
+
+        let error = scrobbler.status().last_error.unwrap();
+        assert!(error.starts_with("400"), "{error}");
+    }
+
+    #[actix_web::test]
+    async fn flush_keeps_listens_the_server_failed_on() {
+        let (url, received) = stub(|_| 500);
+        let scrobbler = scrobbler(&url, vec![listen("Song")]);
+        actix_web::rt::spawn(scrobbler.clone().flush());
+
+        wait_until(|| scrobbler.status().last_error.is_some()).await;
+
+        assert_eq!(received.lock().unwrap().len(), 1);
+        assert_eq!(scrobbler.status().queued, 1);
+        // Retried after the backoff, or sooner when another listen is queued.
+        scrobbler.queued.notify_one();
+        wait_until(|| received.lock().unwrap().len() == 2).await;
+        assert_eq!(scrobbler.status().queued, 1);
+    }
+
+    #[actix_web::test]
+    async fn flush_splits_refused_batches_to_drop_only_the_refused_listen() {
+        let (url, received) = stub(|body| {
+            let refused = body["payload"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|listen| listen["track_metadata"]["track_name"] == "Bad");
+            if refused {
+                400
+            } else {
+                200
+            }
+        });
+        let queue = ["One", "Bad", "Three", "Four"].map(listen).to_vec();
+        let scrobbler = scrobbler(&url, queue);
+        actix_web::rt::spawn(scrobbler.clone().flush());
+
+        wait_until(|| scrobbler.status().queued == 0).await;
+
+        let requests: Vec<Vec<String>> = received
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, _, body)| {
+                body["payload"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|listen| {
+                        listen["track_metadata"]["track_name"]
+                            .as_str()
+                            .unwrap()
+                            .to_string()
+                    })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            requests,
+            [
+                vec!["One", "Bad", "Three", "Four"],
+                vec!["One", "Bad"],
+                vec!["One"],
+                vec!["Bad"],
+                vec!["Three", "Four"],
+            ]
+        );
+    }
+}
@@ -0,0 +1,378 @@
+use crate::media_backend::{MediaBackend, MediaBackendError};
+use crate::media_status::{unix_millis, MediaStatus};
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+const TICK: Duration = Duration::from_secs(1);
+/// The volume is lowered gradually over this last stretch before the timer fires.
+const FADE_DURATION: Duration = Duration::from_secs(60);
+const MAX_MINUTES: f64 = 24.0 * 60.0;
+
+#[derive(Debug)]
+pub enum SleepTimerError {
+    Invalid(String),
+    NotSet,
+    Backend(MediaBackendError),
+}
+
+impl std::fmt::Display for SleepTimerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SleepTimerError::Invalid(e) => write!(f, "{e}"),
+            SleepTimerError::NotSet => write!(f, "No sleep timer set"),
+            SleepTimerError::Backend(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl ResponseError for SleepTimerError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SleepTimerError::Invalid(_) => StatusCode::BAD_REQUEST,
+            SleepTimerError::NotSet => StatusCode::NOT_FOUND,
+            SleepTimerError::Backend(e) => e.status_code(),
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type("text/plain; charset=utf-8")
+            .body(self.to_string())
+    }
+}
+
+impl From<MediaBackendError> for SleepTimerError {
+    fn from(e: MediaBackendError) -> Self {
+        SleepTimerError::Backend(e)
+    }
+}
+
+/// Body of `POST /timer`, either `minutes` or `end_of_track` must be given.
+#[derive(Debug, Deserialize)]
+pub struct TimerRequest {
+    pub minutes: Option<f64>,
+    #[serde(default)]
+    pub end_of_track: bool,
+    #[serde(default)]
+    pub fade: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimerMode {
+    Minutes,
+    EndOfTrack,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TimerStatus {
+    pub mode: TimerMode,
+    pub fade: bool,
+    /// Seconds until the player is paused.
+    pub remaining: f32,
+    /// Server time, in milliseconds since the Unix epoch, at which the timer fires.
+    pub fires_at: u64,
+}
+
+struct TimerState {
+    id: u64,
+    mode: TimerMode,
+    fade: bool,
+    deadline: Instant,
+    /// Track the timer was started on, for `TimerMode::EndOfTrack`.
+    track: String,
+}
+
+impl TimerState {
+    fn status(&self) -> TimerStatus {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        TimerStatus {
+            mode: self.mode,
+            fade: self.fade,
+            remaining: remaining.as_secs_f32(),
+            fires_at: unix_millis(SystemTime::now() + remaining),
+        }
+    }
+}
+
+/// Pauses the player after a delay or at the end of the current track.
+///
+/// The countdown runs on the server, so it keeps going after the client that set it
+/// disconnects. With `fade` the volume is lowered during the last minute and restored once
+/// the player is paused.
+#[derive(Clone)]
+pub struct SleepTimer {
+    backend: MediaBackend,
+    state: Arc<Mutex<Option<TimerState>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl SleepTimer {
+    pub fn new(backend: MediaBackend) -> SleepTimer {
+        SleepTimer {
+            backend,
+            state: Arc::new(Mutex::new(None)),
+            next_id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Starts a timer, replacing the one already running.
+    pub async fn start(&self, request: TimerRequest) -> Result<TimerStatus, SleepTimerError> {
+        let (mode, remaining, track) = match (request.minutes, request.end_of_track) {
+            (Some(minutes), false) => {
+                if !(minutes > 0.0 && minutes <= MAX_MINUTES) {
+                    return Err(SleepTimerError::Invalid(format!(
+                        "Minutes must be between 0 and {MAX_MINUTES}"
+                    )));
+                }
+                let remaining = Duration::from_secs_f64(minutes * 60.0);
+                (TimerMode::Minutes, remaining, String::new())
+            }
+            (None, true) => {
+                let status = self.backend.call(|mc| mc.media_get_status()).await?;
+                if status.duration <= 0.0 {
+                    return Err(SleepTimerError::Invalid(
+                        "The current track has no known duration".to_string(),
+                    ));
+                }
+                let remaining = remaining_in_track(&status);
+                (TimerMode::EndOfTrack, remaining, track_key(&status))
+            }
+            _ => {
+                return Err(SleepTimerError::Invalid(
+                    "Set either minutes or end_of_track".to_string(),
+                ))
+            }
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let state = TimerState {
+            id,
+            mode,
+            fade: request.fade,
+            deadline: Instant::now() + remaining,
+            track,
+        };
+        let status = state.status();
+        *self.state.lock().unwrap() = Some(state);
+
+        actix_web::rt::spawn(self.clone().run(id));
+
+        Ok(status)
+    }
+
+    pub fn status(&self) -> Result<TimerStatus, SleepTimerError> {
+        self.state
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(TimerState::status)
+            .ok_or(SleepTimerError::NotSet)
+    }
+
+    pub fn cancel(&self) -> Result<(), SleepTimerError> {
+        self.state
+            .lock()
+            .unwrap()
+            .take()
+            .map(|_| ())
+            .ok_or(SleepTimerError::NotSet)
+    }
+
+    async fn run(self, id: u64) {
+        // Volume before the fade started, restored when the timer ends or is cancelled.
+        let mut volume_before_fade: Option<f64> = None;
+        let mut fade = true;
+
+        loop {
+            actix_web::rt::time::sleep(TICK).await;
+
+            let (mode, fade_requested, mut remaining, track) = match &*self.state.lock().unwrap() {
+                Some(state) if state.id == id => (
+                    state.mode,
+                    state.fade,
+                    state.deadline.saturating_duration_since(Instant::now()),
+                    state.track.clone(),
+                ),
+                _ => break,
+            };
+
+            if mode == TimerMode::EndOfTrack {
+                match self.backend.call(|mc| mc.media_get_status()).await {
+                    Ok(status) if track_key(&status) != track => remaining = Duration::ZERO,
+                    // Streams can drop their length, the last known end of the track then stands.
+                    Ok(status) if status.duration <= 0.0 => {}
+                    Ok(status) => {
+                        remaining = remaining_in_track(&status);
+                        if let Some(state) = self.state.lock().unwrap().as_mut() {
+                            if state.id == id {
+                                state.deadline = Instant::now() + remaining;
+                            }
+                        }
+                    }
+                    Err(e) => log::warn!("Sleep timer could not read the track position: {e}"),
+                }
+            }
+
+            match step(remaining, fade && fade_requested) {
+                Step::Pause => {
+                    if let Err(e) = self.backend.call(|mc| mc.media_pause()).await {
+                        log::warn!("Sleep timer could not pause the player: {e}");
+                    }
+                    let mut state = self.state.lock().unwrap();
+                    if state.as_ref().is_some_and(|state| state.id == id) {
+                        *state = None;
+                    }
+                    break;
+                }
+                Step::Fade(fraction) => {
+                    let volume = match volume_before_fade {
+                        Some(volume) => volume,
+                        None => match self.backend.call(|mc| mc.media_get_volume()).await {
+                            Ok(volume) => *volume_before_fade.insert(volume),
+                            Err(e) => {
+                                log::warn!("Sleep timer cannot fade out: {e}");
+                                fade = false;
+                                continue;
+                            }
+                        },
+                    };
+                    let faded = volume * fraction;
+                    if let Err(e) = self
+                        .backend
+                        .call(move |mc| mc.media_set_volume(faded))
+                        .await
+                    {
+                        log::warn!("Sleep timer could not lower the volume: {e}");
+                    }
+                }
+                Step::Wait => {}
+            }
+        }
+
+        if let Some(volume) = volume_before_fade {
+            if let Err(e) = self
+                .backend
+                .call(move |mc| mc.media_set_volume(volume))
+                .await
+            {
+                log::warn!("Sleep timer could not restore the volume: {e}");
+            }
+        }
+    }
+}
+
+/// What a running timer does on a tick.
+#[derive(Debug, PartialEq)]
+enum Step {
+    /// Pause the player, the timer is done.
+    Pause,
+    /// Set the volume to this fraction of the volume before the fade.
+    Fade(f64),
+    Wait,
+}
+
+/// The step for a tick with `remaining` left, `fade` telling whether to fade out.
+fn step(remaining: Duration, fade: bool) -> Step {
+    if remaining <= TICK {
+        Step::Pause
+    } else if fade && remaining <= FADE_DURATION {
+        Step::Fade(remaining.as_secs_f64() / FADE_DURATION.as_secs_f64())
+    } else {
+        Step::Wait
+    }
+}
+
+fn remaining_in_track(status: &MediaStatus) -> Duration {
+    let rate = if status.rate > 0.0 { status.rate } else { 1.0 };
+    let remaining = (status.duration - status.position).max(0.0) as f64 / rate;
+    Duration::from_secs_f64(remaining)
+}
+
+fn track_key(status: &MediaStatus) -> String {
+    format!("{}\n{}", status.artist, status.title)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timer() -> SleepTimer {
+        SleepTimer::new(MediaBackend::spawn().unwrap())
+    }
+
+    fn minutes(minutes: f64) -> TimerRequest {
+        TimerRequest {
+            minutes: Some(minutes),
+            end_of_track: false,
+            fade: false,
+        }
+    }
+
+    #[actix_web::test]
+    async fn start_needs_either_minutes_or_end_of_track() {
+        let timer = timer();
+
+        for request in [
+            TimerRequest {
+                minutes: Some(10.0),
+                end_of_track: true,
+                fade: false,
+            },
+            TimerRequest {
+                minutes: None,
+                end_of_track: false,
+                fade: true,
+            },
+        ] {
+            let error = timer.start(request).await.unwrap_err();
+            assert_eq!(error.to_string(), "Set either minutes or end_of_track");
+            assert_eq!(error.status_code(), StatusCode::BAD_REQUEST);
+        }
+        assert!(matches!(timer.status(), Err(SleepTimerError::NotSet)));
+    }
+
+    #[actix_web::test]
+    async fn start_refuses_minutes_out_of_range() {
+        let timer = timer();
+
+        for minutes in [0.0, -5.0, f64::NAN, MAX_MINUTES + 1.0] {
+            let error = timer.start(self::minutes(minutes)).await.unwrap_err();
+            assert_eq!(error.to_string(), "Minutes must be between 0 and 1440");
+        }
+        assert!(matches!(timer.status(), Err(SleepTimerError::NotSet)));
+    }
+
+    #[actix_web::test]
+    async fn cancel_stops_the_running_timer() {
+        let timer = timer();
+
+        let status = timer.start(minutes(10.0)).await.unwrap();
+        assert_eq!(status.mode, TimerMode::Minutes);
+        assert!(status.remaining > 599.0 && status.remaining <= 600.0);
+        assert!(timer.status().is_ok());
+
+        timer.cancel().unwrap();
+        assert!(matches!(timer.status(), Err(SleepTimerError::NotSet)));
+        assert!(matches!(timer.cancel(), Err(SleepTimerError::NotSet)));
+    }
+
+    #[test]
+    fn pauses_once_the_time_is_up() {
+        assert_eq!(step(Duration::ZERO, false), Step::Pause);
+        assert_eq!(step(TICK, true), Step::Pause);
+        assert_eq!(step(TICK * 2, false), Step::Wait);
+        assert_eq!(step(Duration::from_secs(600), true), Step::Wait);
+    }
+
+    #[test]
+    fn fades_out_over_the_last_minute() {
+        assert_eq!(step(FADE_DURATION, true), Step::Fade(1.0));
+        assert_eq!(step(FADE_DURATION / 2, true), Step::Fade(0.5));
+        assert_eq!(step(Duration::from_secs(6), true), Step::Fade(0.1));
+        assert_eq!(step(FADE_DURATION / 2, false), Step::Wait);
+    }
+}
@@ -0,0 +1,112 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::ControlFlow;
+use std::path::PathBuf;
+
+/// Environment variable overriding where the server keeps its state.
+pub const DATA_DIR_ENV: &str = "OSMEDIAMOTE_DATA_DIR";
+
+/// Directory for persisted server state, `$OSMEDIAMOTE_DATA_DIR` or the platform data dir.
+pub fn data_dir() -> PathBuf {
+    match std::env::var_os(DATA_DIR_ENV) {
+        Some(dir) => PathBuf::from(dir),
+        None => dirs::data_dir()
+            .unwrap_or_else(std::env::temp_dir)
+            .join("osmediamote"),
+    }
+}
+
+pub fn data_file(name: &str) -> PathBuf {
+    data_dir().join(name)
+}
+
+/// Reads a JSON file from the data dir, a missing file gives `T::default()`.
+pub fn load_json<T: DeserializeOwned + Default>(name: &str) -> std::io::Result<T> {
+    match std::fs::read(data_file(name)) {
+        Ok(bytes) => serde_json::from_slice(&bytes).map_err(std::io::Error::other),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes a JSON file to the data dir, replacing it atomically.
+pub fn save_json<T: Serialize>(name: &str, value: &T) -> std::io::Result<()> {
+    let path = data_file(name);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    let bytes = serde_json::to_vec_pretty(value).map_err(std::io::Error::other)?;
+    std::fs::write(&tmp_path, bytes)?;
+    std::fs::rename(tmp_path, path)
+}
+
+/// Appends `value` as one line to a JSON Lines file in the data dir.
+pub fn append_json_line<T: Serialize>(name: &str, value: &T) -> std::io::Result<()> {
+    let path = data_file(name);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut line = serde_json::to_vec(value).map_err(std::io::Error::other)?;
+    line.push(b'\n');
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    file.write_all(&line)
+}
+
+/// Reads a JSON Lines file in the data dir from its end, calling `visit` with each entry, newest
+/// first, until it breaks. Lines that do not parse are skipped and a missing file has none.
+///
+/// Only the part of the file up to the last entry visited is read.
+pub fn read_json_lines_rev<T: DeserializeOwned>(
+    name: &str,
+    mut visit: impl FnMut(T) -> ControlFlow<()>,
+) -> std::io::Result<()> {
+    const CHUNK: u64 = 64 * 1024;
+
+    let mut file = match std::fs::File::open(data_file(name)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let mut end = file.metadata()?.len();
+    // First line of the last chunk read, which may begin in the chunk before it.
+    let mut carry = Vec::new();
+    while end > 0 {
+        let start = end.saturating_sub(CHUNK);
+        let mut chunk = vec![0; (end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut chunk)?;
+        chunk.append(&mut carry);
+        end = start;
+
+        let mut lines = chunk.split(|byte| *byte == b'\n').collect::<Vec<_>>();
+        if start > 0 {
+            carry = lines.remove(0).to_vec();
+        }
+        for line in lines.into_iter().rev().filter(|line| !line.is_empty()) {
+            match serde_json::from_slice(line) {
+                Ok(entry) => {
+                    if visit(entry).is_break() {
+                        return Ok(());
+                    }
+                }
+                Err(e) => log::warn!("Skipping a corrupt line in {name}: {e}"),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Points the data dir at a fresh temporary directory, for tests that persist state.
+#[cfg(test)]
+pub fn use_temp_data_dir() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| {
+        let dir = std::env::temp_dir().join(format!("osmediamote-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::env::set_var(DATA_DIR_ENV, dir);
+    });
+}
@@ -0,0 +1,716 @@
+use crate::playback_monitor::{PlaybackEvent, PlaybackMonitor, PlayedTrack};
+use crate::storage;
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use chrono::{DateTime, Local};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+const WEBHOOKS_FILE: &str = "webhooks.json";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Delay before each retry of a failed delivery.
+const RETRY_DELAYS: [Duration; 3] = [
+    Duration::from_secs(1),
+    Duration::from_secs(5),
+    Duration::from_secs(25),
+];
+const FAILURE_LOG_CAPACITY: usize = 50;
+/// Header carrying `sha256=<hex HMAC of the body>` when the webhook has a secret.
+pub const SIGNATURE_HEADER: &str = "X-Signature-256";
+
+#[derive(Debug)]
+pub enum WebhookError {
+    Invalid(String),
+    NotFound(u64),
+    Storage(std::io::Error),
+}
+
+impl std::fmt::Display for WebhookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebhookError::Invalid(e) => write!(f, "{e}"),
+            WebhookError::NotFound(id) => write!(f, "No webhook with id {id}"),
+            WebhookError::Storage(e) => write!(f, "Could not save webhooks: {e}"),
+        }
+    }
+}
+
+impl ResponseError for WebhookError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            WebhookError::Invalid(_) => StatusCode::BAD_REQUEST,
+            WebhookError::NotFound(_) => StatusCode::NOT_FOUND,
+            WebhookError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type("text/plain; charset=utf-8")
+            .body(self.to_string())
+    }
+}
+
+impl From<std::io::Error> for WebhookError {
+    fn from(e: std::io::Error) -> Self {
+        WebhookError::Storage(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    TrackChanged,
+    PlaybackStarted,
+    PlaybackStopped,
+    PlayerAppeared,
+    PlayerGone,
+}
+
+/// Body of `POST /webhooks` and `PUT /webhooks/{id}`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookRequest {
+    pub url: String,
+    /// Events that fire the webhook, all of them when empty.
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+    /// JSON sent instead of the default payload, see [`render`].
+    pub template: Option<Value>,
+    /// Key for the body's HMAC-SHA256, sent in [`SIGNATURE_HEADER`]. Left out on update it keeps
+    /// the current secret, an empty string removes it.
+    pub secret: Option<String>,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    pub id: u64,
+    pub url: String,
+    pub events: Vec<EventKind>,
+    pub template: Option<Value>,
+    pub secret: Option<String>,
+    pub enabled: bool,
+}
+
+impl Webhook {
+    fn wants(&self, event: EventKind) -> bool {
+        self.enabled && (self.events.is_empty() || self.events.contains(&event))
+    }
+
+    fn view(&self) -> WebhookView {
+        WebhookView {
+            id: self.id,
+            url: self.url.clone(),
+            events: self.events.clone(),
+            template: self.template.clone(),
+            has_secret: self.secret.is_some(),
+            enabled: self.enabled,
+        }
+    }
+}
+
+/// A webhook as listed by the API, without its secret.
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookView {
+    pub id: u64,
+    pub url: String,
+    pub events: Vec<EventKind>,
+    pub template: Option<Value>,
+    pub has_secret: bool,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeliveryFailure {
+    pub webhook_id: u64,
+    pub url: String,
+    pub event: EventKind,
+    pub failed_at: DateTime<Local>,
+    pub attempts: usize,
+    pub error: String,
+}
+
+/// What is known about playback when an event fires, the values templates can refer to.
+#[derive(Debug, Clone, Default)]
+struct Context {
+    player: Option<String>,
+    track: Option<PlayedTrack>,
+    is_playing: bool,
+}
+
+struct WebhooksState {
+    webhooks: Vec<Webhook>,
+    next_id: u64,
+    failures: VecDeque<DeliveryFailure>,
+}
+
+impl WebhooksState {
+    /// Saves `webhooks` and only then makes them the current ones, so a failed save changes
+    /// nothing.
+    fn replace(&mut self, webhooks: Vec<Webhook>) -> std::io::Result<()> {
+        storage::save_json(WEBHOOKS_FILE, &webhooks)?;
+        self.webhooks = webhooks;
+        Ok(())
+    }
+}
+
+/// Calls configured URLs when playback changes, e.g. to dim the lights when a movie starts.
+///
+/// Webhooks are kept in `webhooks.json` in the data dir. Failed deliveries are retried a few
+/// times with a growing delay and then recorded in a small in-memory failure log.
+#[derive(Clone)]
+pub struct Webhooks {
+    client: reqwest::Client,
+    state: Arc<Mutex<WebhooksState>>,
+    retry_delays: &'static [Duration],
+}
+
+impl Webhooks {
+    /// Loads the saved webhooks and starts firing them, must be called on the actix runtime.
+    pub fn start(monitor: &PlaybackMonitor) -> std::io::Result<Webhooks> {
+        let webhooks: Vec<Webhook> = storage::load_json(WEBHOOKS_FILE)?;
+        let next_id = webhooks.iter().map(|w| w.id + 1).max().unwrap_or(0);
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(std::io::Error::other)?;
+        let hooks = Webhooks {
+            client,
+            state: Arc::new(Mutex::new(WebhooksState {
+                webhooks,
+                next_id,
+                failures: VecDeque::new(),
+            })),
+            retry_delays: &RETRY_DELAYS,
+        };
+
+        actix_web::rt::spawn(hooks.clone().run(monitor.subscribe()));
+
+        Ok(hooks)
+    }
+
+    pub fn list(&self) -> Vec<WebhookView> {
+        let state = self.state.lock().unwrap();
+        state.webhooks.iter().map(Webhook::view).collect()
+    }
+
+    pub fn get(&self, id: u64) -> Result<WebhookView, WebhookError> {
+        let state = self.state.lock().unwrap();
+        state
+            .webhooks
+            .iter()
+            .find(|webhook| webhook.id == id)
+            .map(Webhook::view)
+            .ok_or(WebhookError::NotFound(id))
+    }
+
+    pub fn add(&self, request: WebhookRequest) -> Result<WebhookView, WebhookError> {
+        validate(&request)?;
+
+        let mut state = self.state.lock().unwrap();
+        let webhook = Webhook {
+            id: state.next_id,
+            url: request.url,
+            events: request.events,
+            template: request.template,
+            secret: request.secret.filter(|secret| !secret.is_empty()),
+            enabled: request.enabled,
+        };
+        let mut webhooks = state.webhooks.clone();
+        webhooks.push(webhook.clone());
+        state.replace(webhooks)?;
+        state.next_id += 1;
+
+        Ok(webhook.view())
+    }
+
+    /// Replaces webhook `id`.
+    pub fn update(&self, id: u64, request: WebhookRequest) -> Result<WebhookView, WebhookError> {
+        validate(&request)?;
+
+        let mut state = self.state.lock().unwrap();
+        let mut webhooks = state.webhooks.clone();
+        let webhook = webhooks
+            .iter_mut()
+            .find(|webhook| webhook.id == id)
+            .ok_or(WebhookError::NotFound(id))?;
+        *webhook = Webhook {
+            id,
+            url: request.url,
+            events: request.events,
+            template: request.template,
+            secret: match request.secret {
+                Some(secret) => Some(secret).filter(|secret| !secret.is_empty()),
+                None => webhook.secret.take(),
+            },
+            enabled: request.enabled,
+        };
+        let webhook = webhook.view();
+        state.replace(webhooks)?;
+
+        Ok(webhook)
+    }
+
+    pub fn remove(&self, id: u64) -> Result<(), WebhookError> {
+        let mut state = self.state.lock().unwrap();
+        let mut webhooks = state.webhooks.clone();
+        webhooks.retain(|webhook| webhook.id != id);
+        if webhooks.len() == state.webhooks.len() {
+            return Err(WebhookError::NotFound(id));
+        }
+        state.replace(webhooks)?;
+
+        Ok(())
+    }
+
+    /// Deliveries that failed after all retries, oldest first.
+    pub fn failures(&self) -> Vec<DeliveryFailure> {
+        let state = self.state.lock().unwrap();
+        state.failures.iter().cloned().collect()
+    }
+
+    async fn run(self, mut events: tokio::sync::broadcast::Receiver<PlaybackEvent>) {
+        let mut context = Context::default();
+
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => {
+                    log::warn!("Webhooks missed {missed} playback events");
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            let kind = match event {
+                PlaybackEvent::PlayerAppeared { player } => {
+                    context.player = Some(player);
+                    EventKind::PlayerAppeared
+                }
+                PlaybackEvent::PlayerGone { player } => {
+                    context.player = Some(player);
+                    context.track = None;
+                    EventKind::PlayerGone
+                }
+                PlaybackEvent::TrackStarted(track) => {
+                    context.track = Some(track);
+                    EventKind::TrackChanged
+                }
+                PlaybackEvent::PlayingChanged { is_playing } => {
+                    context.is_playing = is_playing;
+                    if is_playing {
+                        EventKind::PlaybackStarted
+                    } else {
+                        EventKind::PlaybackStopped
+                    }
+                }
+                PlaybackEvent::TrackEnded { .. } => continue,
+            };
+
+            let targets: Vec<Webhook> = {
+                let state = self.state.lock().unwrap();
+                state
+                    .webhooks
+                    .iter()
+                    .filter(|webhook| webhook.wants(kind))
+                    .cloned()
+                    .collect()
+            };
+            if targets.is_empty() {
+                continue;
+            }
+
+            let fields = fields(kind, &context);
+            for webhook in targets {
+                let body = match &webhook.template {
+                    Some(template) => render(template, &fields),
+                    None => Value::Object(fields.clone()),
+                };
+                actix_web::rt::spawn(self.clone().deliver(webhook, kind, body));
+            }
+        }
+    }
+
+    async fn deliver(self, webhook: Webhook, event: EventKind, body: Value) {
+        let body = body.to_string();
+        let mut attempts = 0;
+
+        let error = loop {
+            attempts += 1;
+            let error = match self.send(&webhook, &body).await {
+                Ok(()) => return,
+                Err(e) => e,
+            };
+            match self.retry_delays.get(attempts - 1) {
+                Some(delay) => actix_web::rt::time::sleep(*delay).await,
+                None => break error,
+            }
+        };
+
+        log::warn!(
+            "Webhook {} failed after {attempts} attempts: {error}",
+            webhook.id
+        );
+        let mut state = self.state.lock().unwrap();
+        if state.failures.len() == FAILURE_LOG_CAPACITY {
+            state.failures.pop_front();
+        }
+        state.failures.push_back(DeliveryFailure {
+            webhook_id: webhook.id,
+            url: webhook.url,
+            event,
+            failed_at: Local::now(),
+            attempts,
+            error,
+        });
+    }
+
+    async fn send(&self, webhook: &Webhook, body: &str) -> Result<(), String> {
+        let mut request = self
+            .client
+            .post(&webhook.url)
+            .header("Content-Type", "application/json")
+            .body(body.to_owned());
+        if let Some(secret) = &webhook.secret {
+            request = request.header(SIGNATURE_HEADER, signature(secret, body));
+        }
+
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("{} answered {}", webhook.url, response.status()))
+        }
+    }
+}
+
+fn validate(request: &WebhookRequest) -> Result<(), WebhookError> {
+    match url::Url::parse(&request.url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(()),
+        _ => Err(WebhookError::Invalid(format!(
+            "Invalid webhook URL: {}",
+            request.url
+        ))),
+    }
+}
+
+/// The default payload, and the values `{{name}}` placeholders in templates are replaced with.
+fn fields(event: EventKind, context: &Context) -> serde_json::Map<String, Value> {
+    let track = context.track.as_ref();
+    let Value::Object(fields) = json!({
+        "event": event,
+        "timestamp": Local::now(),
+        "player": context.player,
+        "is_playing": context.is_playing,
+        "title": track.map(|t| &t.title),
+        "artist": track.map(|t| &t.artist),
+        "album": track.map(|t| &t.album),
+        "duration": track.map(|t| t.duration),
+    }) else {
+        unreachable!()
+    };
+    fields
+}
+
+/// Fills a payload template.
+///
+/// A string that is exactly `"{{name}}"` is replaced with the field's JSON value, so numbers and
+/// booleans keep their type, while placeholders inside longer strings are replaced with text.
+/// Spaces around names are ignored and unknown placeholders are left as they are.
+pub fn render(template: &Value, fields: &serde_json::Map<String, Value>) -> Value {
+    match template {
+        Value::String(text) => {
+            let pieces = pieces(text);
+            if let [Piece::Placeholder { name, .. }] = pieces[..] {
+                if let Some(value) = fields.get(name) {
+                    return value.clone();
+                }
+            }
+
+            let mut rendered = String::with_capacity(text.len());
+            for piece in pieces {
+                match piece {
+                    Piece::Text(text) => rendered.push_str(text),
+                    Piece::Placeholder { name, raw } => match fields.get(name) {
+                        Some(Value::String(s)) => rendered.push_str(s),
+                        Some(Value::Null) => {}
+                        Some(other) => rendered.push_str(&other.to_string()),
+                        None => rendered.push_str(raw),
+                    },
+                }
+            }
+            Value::String(rendered)
+        }
+        Value::Array(items) => Value::Array(items.iter().map(|v| render(v, fields)).collect()),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| (k.clone(), render(v, fields)))
+                .collect(),
+        ),
+        other => other.clone(),
+    }
+}
+
+enum Piece<'a> {
+    Text(&'a str),
+    /// `raw` is the whole `{{ name }}`, kept when there is no such field.
+    Placeholder {
+        name: &'a str,
+        raw: &'a str,
+    },
+}
+
+/// Splits a template string into text and `{{name}}` placeholders.
+fn pieces(mut text: &str) -> Vec<Piece<'_>> {
+    let mut pieces = Vec::new();
+    while let Some(mut start) = text.find("{{") {
+        let Some(len) = text[start + 2..].find("}}") else {
+            break;
+        };
+        let end = start + 2 + len + 2;
+        // In `{{ {{name}}` the placeholder is the innermost one.
+        if let Some(inner) = text[start + 2..end - 2].rfind("{{") {
+            start += 2 + inner;
+        }
+        if start > 0 {
+            pieces.push(Piece::Text(&text[..start]));
+        }
+        pieces.push(Piece::Placeholder {
+            name: text[start + 2..end - 2].trim(),
+            raw: &text[start..end],
+        });
+        text = &text[end..];
+    }
+    if !text.is_empty() {
+        pieces.push(Piece::Text(text));
+    }
+    pieces
+}
+
+fn signature(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body.as_bytes());
+    let digest = mac.finalize().into_bytes();
+    let hex: String = digest.iter().map(|b| format!("{b:02x}")).collect();
+    format!("sha256={hex}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpRequest, HttpServer};
+
+    const SHORT_RETRY_DELAYS: [Duration; 3] = [Duration::from_millis(10); 3];
+
+    /// A request received by the stub server: its signature header and body.
+    type Received = (Option<String>, String);
+
+    /// Starts a webhook receiver answering with `statuses` in turn, repeating the last one,
+    /// returning its URL and the requests it received.
+    fn stub(statuses: &'static [u16]) -> (String, Arc<Mutex<Vec<Received>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+
+        let log = web::Data::new(received.clone());
+        let server = HttpServer::new(move || {
+            App::new().app_data(log.clone()).default_service(web::to(
+                    move |req: HttpRequest,
+                          body: String,
+                          log: web::Data<Arc<Mutex<Vec<Received>>>>| async move {
+                        let signature = req
+                            .headers()
+                            .get(SIGNATURE_HEADER)
+                            .and_then(|value| value.to_str().ok())
+                            .map(str::to_string);
+                        let mut log = log.lock().unwrap();
+                        log.push((signature, body));
+                        let status = statuses[(log.len() - 1).min(statuses.len() - 1)];
+                        HttpResponse::build(StatusCode::from_u16(status).unwrap()).finish()
+                    },
+                ))
+        })
+        .workers(1)
+        .disable_signals()
+        .listen(listener)
+        .unwrap()
+        .run();
+        actix_web::rt::spawn(server);
+
+        (url, received)
+    }
+
+    fn webhooks() -> Webhooks {
+        Webhooks {
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .unwrap(),
+            state: Arc::new(Mutex::new(WebhooksState {
+                webhooks: Vec::new(),
+                next_id: 0,
+                failures: VecDeque::new(),
+            })),
+            retry_delays: &SHORT_RETRY_DELAYS,
+        }
+    }
+
+    fn webhook(url: &str, secret: Option<&str>) -> Webhook {
+        Webhook {
+            id: 7,
+            url: url.to_string(),
+            events: Vec::new(),
+            template: None,
+            secret: secret.map(str::to_string),
+            enabled: true,
+        }
+    }
+
+    fn fields() -> serde_json::Map<String, Value> {
+        let Value::Object(fields) = json!({
+            "title": "Song",
+            "duration": 200.5,
+            "is_playing": true,
+            "album": null,
+        }) else {
+            unreachable!()
+        };
+        fields
+    }
+
+    #[test]
+    fn whole_placeholders_keep_the_value_type() {
+        let template = json!({
+            "duration": "{{duration}}",
+            "playing": "{{ is_playing }}",
+            "album": "{{album}}",
+            "nested": ["{{title}}"],
+        });
+
+        assert_eq!(
+            render(&template, &fields()),
+            json!({
+                "duration": 200.5,
+                "playing": true,
+                "album": null,
+                "nested": ["Song"],
+            })
+        );
+    }
+
+    #[test]
+    fn placeholders_in_text_are_replaced_with_text() {
+        let template = json!("{{title}} ({{ duration }}s){{album}}, playing: {{  is_playing}}");
+
+        assert_eq!(
+            render(&template, &fields()),
+            json!("Song (200.5s), playing: true")
+        );
+    }
+
+    #[test]
+    fn unknown_and_unclosed_placeholders_are_left_alone() {
+        assert_eq!(render(&json!("{{nope}}"), &fields()), json!("{{nope}}"));
+        assert_eq!(
+            render(&json!("{{ nope }} {{title}}"), &fields()),
+            json!("{{ nope }} Song")
+        );
+        assert_eq!(
+            render(&json!("{{title}} {{title"), &fields()),
+            json!("Song {{title")
+        );
+        assert_eq!(
+            render(&json!("{{ {{title}} }}"), &fields()),
+            json!("{{ Song }}")
+        );
+        assert_eq!(render(&json!(42), &fields()), json!(42));
+    }
+
+    #[test]
+    fn signature_is_the_hex_hmac_of_the_body() {
+        assert_eq!(
+            signature("key", "The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[actix_web::test]
+    async fn deliver_posts_the_signed_body() {
+        let (url, received) = stub(&[200]);
+        let webhooks = webhooks();
+
+        webhooks
+            .clone()
+            .deliver(
+                webhook(&url, Some("key")),
+                EventKind::TrackChanged,
+                json!({"title": "Song"}),
+            )
+            .await;
+
+        let received = received.lock().unwrap();
+        let [(signature, body)] = received.as_slice() else {
+            panic!("expected one request, got {received:?}");
+        };
+        assert_eq!(body, r#"{"title":"Song"}"#);
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"key").unwrap();
+        mac.update(body.as_bytes());
+        let hex: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        assert_eq!(signature.as_deref(), Some(format!("sha256={hex}").as_str()));
+        assert!(webhooks.failures().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn deliver_retries_after_server_errors() {
+        let (url, received) = stub(&[503, 200]);
+        let webhooks = webhooks();
+
+        webhooks
+            .clone()
+            .deliver(webhook(&url, None), EventKind::PlaybackStarted, json!({}))
+            .await;
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].0, None);
+        assert!(webhooks.failures().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn deliver_logs_a_failure_once_retries_run_out() {
+        let (url, received) = stub(&[500]);
+        let webhooks = webhooks();
+
+        webhooks
+            .clone()
+            .deliver(webhook(&url, None), EventKind::PlayerGone, json!({}))
+            .await;
+
+        assert_eq!(received.lock().unwrap().len(), 4);
+        let failures = webhooks.failures();
+        let [failure] = failures.as_slice() else {
+            panic!("expected one failure, got {failures:?}");
+        };
+        assert_eq!(failure.webhook_id, 7);
+        assert_eq!(failure.url, url);
+        assert_eq!(failure.event, EventKind::PlayerGone);
+        assert_eq!(failure.attempts, 4);
+        assert!(failure.error.contains("500"), "{}", failure.error);
+    }
+}